@@ -1,14 +1,13 @@
 use ic_cdk::{
-    caller,
     update,
     query,
     storage,
-    trap, 
+    trap,
 };
-use candid::{self, CandidType, Deserialize, Principal};
-use ic_cdk_macros::{pre_upgrade, post_upgrade};
+use candid::{self, CandidType, Deserialize, Nat, Principal, decode_one, encode_one};
+use ic_cdk_macros::{init, pre_upgrade, post_upgrade};
 
-use std::{collections::BTreeMap, sync::Mutex};
+use std::{collections::{BTreeMap, BTreeSet}, sync::Mutex};
 
 
 // represent an item listed for auction
@@ -22,6 +21,23 @@ pub struct Item {
     highest_bidder: Option<Principal>, 
     active: bool, 
     new_owner: Option<Principal>,
+    // smallest raise over the current highest bid, set by admins
+    min_increment: Option<u64>,
+    // floor for the very first bid, set by the owner
+    opening_bid: Option<u64>,
+    // bidders must have been first seen at least this many seconds ago
+    min_account_age_secs: Option<u64>,
+    // if set, only these principals can win the item at settlement
+    allowed_buyers: Option<BTreeSet<Principal>>,
+    // deadline for timed auctions (ns since epoch), None for manual-stop auctions
+    end_time: Option<u64>,
+    // when the item was listed and when its auction closed (ns since epoch)
+    listed_at: Option<u64>,
+    closed_at: Option<u64>,
+    // what the seller was paid after the ledger fee, None for unsold items and sales before escrow
+    seller_proceeds: Option<u64>,
+    // why an admin cancelled the auction, None unless admin_cancel_auction closed it
+    cancel_reason: Option<String>,
 }
 
 // Rep. a bid on an item
@@ -29,31 +45,724 @@ pub struct Item {
 pub struct Bid {
     bidder: Principal,
     amount: u64,
+    // time the bid was placed (ns since epoch), None for bids placed before this was recorded
+    timestamp: Option<u64>,
+}
+
+// one step in an item's history, returned by get_item_timeline
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TimelineEvent {
+    Listed { at: u64, owner: Principal },
+    BidPlaced { at: Option<u64>, bidder: Principal, amount: u64 },
+    Closed { at: u64, new_owner: Option<Principal>, price: u64, cancel_reason: Option<String> },
+}
+
+// one page of items returned by list_items_paged
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ItemPage {
+    items: Vec<Item>,
+    // number of items matching the filter across all pages
+    total: u64,
+    next_offset: Option<u64>,
+}
+
+// whether the caller can bid on an item, and why not
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Biddability {
+    Biddable,
+    NotBiddable { reason: String },
+}
+
+// result of closing an auction
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum StopOutcome {
+    Sold { buyer: Principal, price: u64 },
+    Unsold,
+}
+
+// marketplace-wide settings, adjustable by admins
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MarketConfig {
+    // whether owners may still edit these fields once an item has bids
+    allow_description_edit_with_bids: bool,
+    allow_name_edit_with_bids: bool,
+    // rejects new listings while existing auctions keep running
+    listings_paused: bool,
+    // most active auctions one principal may lead at once, None for no limit
+    max_simultaneous_leads: Option<u64>,
+}
+
+impl MarketConfig {
+    const fn new() -> Self {
+        MarketConfig {
+            allow_description_edit_with_bids: true,
+            allow_name_edit_with_bids: false,
+            listings_paused: false,
+            max_simultaneous_leads: None,
+        }
+    }
+
+    // (name, value) of every config field as text, optional values shown as a number or "none"
+    fn fields(&self) -> [(&'static str, String); 4] {
+        // no `..`, so adding a config field fails to compile until it is listed here
+        let MarketConfig { allow_description_edit_with_bids, allow_name_edit_with_bids, listings_paused, max_simultaneous_leads } = self;
+        [
+            ("allow_description_edit_with_bids", allow_description_edit_with_bids.to_string()),
+            ("allow_name_edit_with_bids", allow_name_edit_with_bids.to_string()),
+            ("listings_paused", listings_paused.to_string()),
+            ("max_simultaneous_leads", max_simultaneous_leads.map_or_else(|| "none".to_string(), |max| max.to_string())),
+        ]
+    }
+
+    // (name, value) of every field that differs from `defaults`
+    fn overrides(&self, defaults: &MarketConfig) -> Vec<(String, String)> {
+        self.fields().into_iter()
+            .zip(defaults.fields())
+            .filter(|((_, current), (_, default))| current != default)
+            .map(|((name, current), _)| (name.to_string(), current))
+            .collect()
+    }
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        MarketConfig::new()
+    }
+}
+
+// a user's buying and selling totals, returned by get_user_summary
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UserSummary {
+    total_spent: u64,
+    total_earned: u64,
+    items_listed: u64,
+    items_won: u64,
+    locked_escrow: u64,
+    available_balance: u64,
+}
+
+// operator overview returned by admin_dashboard
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AdminDashboard {
+    config: MarketConfig,
+    total_items: u64,
+    active_items: u64,
+    sold_items: u64,
+    total_bids: u64,
+    unique_bidders: u64,
+    total_deposits: u64,
+    stable_memory_pages: u64,
+    cycle_balance: u128,
+    deployed_at: u64,
+}
+
+// portable copy of one listing, produced by export_item and consumed by import_item
+#[derive(CandidType, Deserialize)]
+struct ItemExport {
+    item: Item,
+    bids: Vec<Bid>,
+    bid_log: Vec<Bid>,
+}
+
+// arguments passed when the canister is installed
+#[derive(CandidType, Deserialize)]
+pub struct InitArgs {
+    // ICRC-1/ICRC-2 ledger holding the tokens used for bidding
+    ledger_canister_id: Principal,
+}
+
+// ICRC ledger interface, only the parts used for escrow
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
 }
 
 // main state of  canister
-#[derive(CandidType, Deserialize)] 
+#[derive(CandidType)] 
 struct CanisterState {
+    items: BTreeMap<u64, Item>,
+    item_bids: BTreeMap<u64, BTreeMap<Principal, Bid>>,
+    // append-only history of every accepted bid per item, oldest first
+    bid_log: BTreeMap<u64, Vec<Bid>>,
+    next_item_id: u64,
+    // item ids each principal has ever bid on
+    participation: BTreeMap<Principal, BTreeSet<u64>>,
+    // time each principal first made an update call, as a stand-in for account age
+    first_seen: BTreeMap<Principal, u64>,
+    config: MarketConfig,
+    // time of the initial install, kept across upgrades; 0 if installed before this was recorded
+    deployed_at: u64,
+    ledger_canister_id: Option<Principal>,
+    // tokens each principal holds in the canister's ledger account; bids on active items lock part of it
+    deposits: BTreeMap<Principal, u64>,
+    // last fee reported by the ledger, so previews apply the same fee as a real settlement
+    last_ledger_fee: Option<u64>,
+}
+
+impl CanisterState {
+    const fn new() -> Self {
+        CanisterState {
+            items: BTreeMap::new(),
+            item_bids: BTreeMap::new(),
+            bid_log: BTreeMap::new(),
+            next_item_id: 0,
+            participation: BTreeMap::new(),
+            first_seen: BTreeMap::new(),
+            config: MarketConfig::new(),
+            deployed_at: 0,
+            ledger_canister_id: None,
+            deposits: BTreeMap::new(),
+            last_ledger_fee: None,
+        }
+    }
+}
+
+// CanisterState as read back from stable memory. Fields added after the first release are
+// optional, so state saved by an older version still decodes.
+#[derive(CandidType, Deserialize)]
+struct StableState {
     items: BTreeMap<u64, Item>,
     item_bids: BTreeMap<u64, BTreeMap<Principal, Bid>>,
     next_item_id: u64,
+    bid_log: Option<BTreeMap<u64, Vec<Bid>>>,
+    participation: Option<BTreeMap<Principal, BTreeSet<u64>>>,
+    first_seen: Option<BTreeMap<Principal, u64>>,
+    config: Option<MarketConfig>,
+    deployed_at: Option<u64>,
+    ledger_canister_id: Option<Principal>,
+    deposits: Option<BTreeMap<Principal, u64>>,
+    last_ledger_fee: Option<u64>,
+}
+
+impl From<StableState> for CanisterState {
+    fn from(stable: StableState) -> Self {
+        let StableState { items, item_bids, next_item_id, bid_log, participation, first_seen, config, deployed_at, ledger_canister_id, deposits, last_ledger_fee } = stable;
+
+        // state saved before participation was tracked: rebuild it from the standing bids
+        let participation = participation.unwrap_or_else(|| {
+            let mut participation: BTreeMap<Principal, BTreeSet<u64>> = BTreeMap::new();
+            for (item_id, bids_map) in &item_bids {
+                for bidder in bids_map.keys() {
+                    participation.entry(*bidder).or_default().insert(*item_id);
+                }
+            }
+            participation
+        });
+
+        CanisterState {
+            items,
+            item_bids,
+            bid_log: bid_log.unwrap_or_default(),
+            next_item_id,
+            participation,
+            first_seen: first_seen.unwrap_or_default(),
+            config: config.unwrap_or_default(),
+            deployed_at: deployed_at.unwrap_or_default(),
+            ledger_canister_id,
+            deposits: deposits.unwrap_or_default(),
+            last_ledger_fee,
+        }
+    }
 }
 
+// maximum number of items accepted by a single list_items call
+const MAX_BATCH_LISTING: usize = 50;
+
+// largest page returned by paginated queries
+const MAX_PAGE_SIZE: u64 = 100;
+
 // initialize the state as a thread-local static.
 thread_local! {
-    static STATE: Mutex<CanisterState> = Mutex::new(CanisterState {
-        items: BTreeMap::new(),
-        item_bids: BTreeMap::new(),
-        next_item_id: 0,
-    });
+    static STATE: Mutex<CanisterState> = const { Mutex::new(CanisterState::new()) };
+    // items whose settlement is waiting on the ledger; not persisted across upgrades
+    static SETTLING: Mutex<BTreeSet<u64>> = const { Mutex::new(BTreeSet::new()) };
 }
 
 
 // Get current caller's principal
+#[cfg(not(test))]
+fn get_caller() -> Principal {
+    ic_cdk::caller()
+}
+
+#[cfg(test)]
 fn get_caller() -> Principal {
-    caller()
+    tests::CALLER.with(|caller| caller.get())
+}
+
+// Get current time (ns since epoch)
+#[cfg(not(test))]
+fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+fn now() -> u64 {
+    tests::NOW.with(|now| now.get())
+}
+
+#[cfg(not(test))]
+fn is_admin(who: Principal) -> bool {
+    ic_cdk::api::is_controller(&who)
+}
+
+#[cfg(test)]
+fn is_admin(who: Principal) -> bool {
+    tests::ADMINS.with(|admins| admins.borrow().contains(&who))
+}
+
+// Only canister controllers may act as marketplace admins
+fn require_admin() -> Result<(), String> {
+    if is_admin(get_caller()) {
+        Ok(())
+    } else {
+        Err("Only an admin can perform this action.".to_string())
+    }
+}
+
+// Remember when a principal first interacted with the marketplace
+fn touch_first_seen(state: &mut CanisterState, who: Principal) {
+    state.first_seen.entry(who).or_insert_with(now);
+}
+
+// Checks that decide whether a principal may bid on an item at all, regardless of amount
+fn check_can_bid(state: &CanisterState, item: &Item, bidder: Principal, now: u64) -> Result<(), String> {
+    if !item.active {
+        return Err("Auction for this item is no longer active.".to_string());
+    }
+    if is_expired(item, now) {
+        return Err("Auction has ended.".to_string());
+    }
+    if is_settling(item.id) {
+        return Err("Settlement for this item is in progress.".to_string());
+    }
+    if item.owner == bidder {
+        return Err("Cannot bid on your own item.".to_string());
+    }
+    if let Some(min_age_secs) = item.min_account_age_secs {
+        let first_seen = state.first_seen.get(&bidder).copied().unwrap_or(now);
+        let age_secs = now.saturating_sub(first_seen) / 1_000_000_000;
+        if age_secs < min_age_secs {
+            return Err(format!("This auction requires bidders to have been active for at least {} seconds.", min_age_secs));
+        }
+    }
+    if let Some(max_leads) = state.config.max_simultaneous_leads {
+        if item.highest_bidder != Some(bidder) && leading_count(state, bidder) >= max_leads {
+            return Err(format!("You are already leading the maximum of {} auctions.", max_leads));
+        }
+    }
+    Ok(())
+}
+
+// Validate a bid against the auction rules and the bidder's unlocked deposit, then record it
+fn place_bid(state: &mut CanisterState, item_id: u64, bidder: Principal, amount: u64, now: u64) -> Result<(), String> {
+    let mut item = state.items.get(&item_id)
+        .ok_or_else(|| "Item not found.".to_string())?
+        .clone(); 
+
+    check_can_bid(state, &item, bidder, now)?;
+    if amount <= item.current_highest_bid {
+        return Err(format!("Bid amount ({}) must be higher than the current highest bid ({}).", amount, item.current_highest_bid));
+    }
+    if let Some(opening_bid) = item.opening_bid {
+        if item.highest_bidder.is_none() && amount < opening_bid {
+            return Err(format!("The first bid must be at least the opening bid ({}).", opening_bid));
+        }
+    }
+    if let Some(increment) = item.min_increment {
+        if item.highest_bidder.is_some() && amount < item.current_highest_bid.saturating_add(increment) {
+            return Err(format!("Bid must raise the current highest bid ({}) by at least {}.", item.current_highest_bid, increment));
+        }
+    }
+
+    // the bidder's previous bid on this item is replaced, so its funds count towards the new one
+    let previous_bid = state.item_bids.get(&item_id)
+        .and_then(|bids| bids.get(&bidder))
+        .map_or(0, |bid| bid.amount);
+    let available = available_balance(state, bidder).saturating_add(previous_bid);
+    if available < amount {
+        return Err(format!("Insufficient deposit: {} available, {} required.", available, amount));
+    }
+
+    //  Modify the cloned 'item'.
+    item.current_highest_bid = amount;
+    item.highest_bidder = Some(bidder);
+
+    // Update the original item in the BTreeMap with the modified clone.
+    state.items.insert(item_id, item); 
+
+    let item_bids_map = state.item_bids.entry(item_id).or_default();
+
+    let new_bid = Bid {
+        bidder,
+        amount,
+        timestamp: Some(now),
+    };
+    item_bids_map.insert(bidder, new_bid.clone()); 
+
+    state.bid_log.entry(item_id).or_default().push(new_bid);
+
+    state.participation.entry(bidder).or_default().insert(item_id);
+
+    Ok(())
+}
+
+// Drop a bidder's standing bid; if they were leading, the next best bid takes over.
+// A runner-up already leading as many auctions as the cap allows cannot take over, and their bid is
+// dropped too, as it would otherwise still win at settlement without counting towards the cap.
+fn remove_bid(state: &mut CanisterState, item_id: u64, bidder: Principal) -> Result<(), String> {
+    let bids_map = state.item_bids.entry(item_id).or_default();
+    if bids_map.remove(&bidder).is_none() {
+        return Err("You have no bid on this item.".to_string());
+    }
+
+    let was_leading = state.items.get(&item_id).is_some_and(|item| item.highest_bidder == Some(bidder));
+    if !was_leading {
+        return Ok(());
+    }
+
+    let new_leader = loop {
+        let candidate = state.item_bids.get(&item_id)
+            .and_then(|bids_map| bids_map.values()
+                .max_by(|a, b| a.amount.cmp(&b.amount).then(b.timestamp.cmp(&a.timestamp))))
+            .map(|bid| (bid.bidder, bid.amount));
+        match (candidate, state.config.max_simultaneous_leads) {
+            (Some((runner_up, _)), Some(max_leads)) if leading_count(state, runner_up) >= max_leads => {
+                if let Some(bids_map) = state.item_bids.get_mut(&item_id) {
+                    bids_map.remove(&runner_up);
+                }
+                ic_cdk::println!("Bid of {} on item {} dropped: already leading {} auctions", runner_up, item_id, max_leads);
+            },
+            _ => break candidate,
+        }
+    };
+    if let Some(item) = state.items.get_mut(&item_id) {
+        item.current_highest_bid = new_leader.map_or(0, |(_, amount)| amount);
+        item.highest_bidder = new_leader.map(|(leader, _)| leader);
+    }
+
+    Ok(())
+}
+
+// Number of active auctions a principal is currently leading
+fn leading_count(state: &CanisterState, who: Principal) -> u64 {
+    state.items.values()
+        .filter(|item| item.active && item.highest_bidder == Some(who))
+        .count() as u64
+}
+
+// Insert a fresh active listing and return its id
+fn create_item(state: &mut CanisterState, owner: Principal, name: String, description: String, end_time: Option<u64>) -> u64 {
+    let item_id = state.next_item_id;
+    state.next_item_id += 1;
+
+    let new_item = Item {
+        id: item_id,
+        owner,
+        name,
+        description,
+        current_highest_bid: 0,
+        highest_bidder: None,
+        active: true,
+        new_owner: None,
+        min_increment: None,
+        opening_bid: None,
+        min_account_age_secs: None,
+        allowed_buyers: None,
+        end_time,
+        listed_at: Some(now()),
+        closed_at: None,
+        seller_proceeds: None,
+        cancel_reason: None,
+    };
+
+    state.items.insert(item_id, new_item);
+    state.item_bids.insert(item_id, BTreeMap::new()); 
+
+    ic_cdk::println!("Item listed: {} by {}", item_id, owner);
+    item_id
+}
+
+// Item ids that have a bids entry but no matching item
+fn orphaned_bid_ids(state: &CanisterState) -> Vec<u64> {
+    state.item_bids.keys()
+        .filter(|item_id| !state.items.contains_key(item_id))
+        .cloned()
+        .collect()
+}
+
+// An item counts as sold once its auction closed with a new owner
+fn is_sold(item: &Item) -> bool {
+    !item.active && item.new_owner.is_some()
+}
+
+// Winning prices of all sales, excluding zero-price ones
+fn sale_prices(state: &CanisterState) -> Vec<u64> {
+    state.items.values()
+        .filter(|item| is_sold(item) && item.current_highest_bid > 0)
+        .map(|item| item.current_highest_bid)
+        .collect()
+}
+
+// Decide how an item would settle if its auction closed now with the given ledger fee.
+// The best bid wins among those that can actually be paid out: from an approved buyer when an
+// allowlist applies, covered by the bidder's deposit, and above the fee. That is the leader
+// unless one of these rules excludes it.
+fn settlement_outcome(state: &CanisterState, item: &Item, fee: u64) -> StopOutcome {
+    state.item_bids.get(&item.id).into_iter()
+        .flat_map(|bids_map| bids_map.values())
+        .filter(|bid| item.allowed_buyers.as_ref().is_none_or(|allowed_buyers| allowed_buyers.contains(&bid.bidder)))
+        .filter(|bid| state.deposits.get(&bid.bidder).copied().unwrap_or(0) >= bid.amount)
+        .filter(|bid| bid.amount > fee)
+        .max_by(|a, b| a.amount.cmp(&b.amount).then(b.timestamp.cmp(&a.timestamp)))
+        .map_or(StopOutcome::Unsold, |bid| StopOutcome::Sold { buyer: bid.bidder, price: bid.amount })
+}
+
+// Mark an auction closed and apply its settlement outcome.
+// The winning bid is recorded on the item, as it differs from the leader when a bid is excluded.
+fn close_auction(item: &mut Item, outcome: &StopOutcome, now: u64) {
+    item.active = false;
+    item.closed_at = Some(now);
+    match *outcome {
+        StopOutcome::Sold { buyer, price } => {
+            item.highest_bidder = Some(buyer);
+            item.current_highest_bid = price;
+            item.new_owner = Some(buyer);
+        },
+        StopOutcome::Unsold => {
+            item.new_owner = None;
+        },
+    }
+}
+
+// A timed auction has expired once its end time is reached
+fn is_expired(item: &Item, now: u64) -> bool {
+    item.end_time.is_some_and(|end_time| now >= end_time)
+}
+
+// Funds held by standing bids on active auctions
+fn locked_balance(state: &CanisterState, who: Principal) -> u64 {
+    state.items.values()
+        .filter(|item| item.active)
+        .filter_map(|item| state.item_bids.get(&item.id)?.get(&who))
+        .fold(0, |locked, bid| locked.saturating_add(bid.amount))
+}
+
+// Deposited funds not locked by any standing bid
+fn available_balance(state: &CanisterState, who: Principal) -> u64 {
+    let deposited = state.deposits.get(&who).copied().unwrap_or(0);
+    deposited.saturating_sub(locked_balance(state, who))
+}
+
+fn is_settling(item_id: u64) -> bool {
+    SETTLING.with(|settling| settling.lock().unwrap().contains(&item_id))
+}
+
+// Marks an item as settling for as long as it lives.
+// Dropping it also runs during cleanup if the callback traps, so items never stay stuck.
+struct SettlementGuard(u64);
+
+impl SettlementGuard {
+    fn new(item_id: u64) -> Self {
+        SETTLING.with(|settling| settling.lock().unwrap().insert(item_id));
+        SettlementGuard(item_id)
+    }
+}
+
+impl Drop for SettlementGuard {
+    fn drop(&mut self) {
+        SETTLING.with(|settling| settling.lock().unwrap().remove(&self.0));
+    }
+}
+
+// a sale picked by begin_settlement, waiting for the seller's payout
+struct PendingSale {
+    seller: Principal,
+    buyer: Principal,
+    price: u64,
+    fee: u64,
+}
+
+// Decide how to close an auction before any payout. An unsold auction is closed right away;
+// a sale is returned for payout and only recorded by complete_sale once the seller was paid.
+fn begin_settlement(state: &mut CanisterState, item_id: u64, fee: u64, now: u64) -> Result<Option<PendingSale>, String> {
+    let item = state.items.get(&item_id)
+        .ok_or_else(|| "Item not found.".to_string())?;
+    if !item.active {
+        return Err("Listing is already stopped.".to_string());
+    }
+
+    match settlement_outcome(state, item, fee) {
+        StopOutcome::Sold { buyer, price } => Ok(Some(PendingSale { seller: item.owner, buyer, price, fee })),
+        StopOutcome::Unsold => {
+            if let Some(item) = state.items.get_mut(&item_id) {
+                close_auction(item, &StopOutcome::Unsold, now);
+            }
+            Ok(None)
+        },
+    }
+}
+
+// Record a sale whose payout went through: charge the buyer's deposit and close the auction
+fn complete_sale(state: &mut CanisterState, item_id: u64, sale: &PendingSale, now: u64) {
+    if let Some(balance) = state.deposits.get_mut(&sale.buyer) {
+        *balance = balance.saturating_sub(sale.price);
+    }
+    if let Some(item) = state.items.get_mut(&item_id) {
+        close_auction(item, &StopOutcome::Sold { buyer: sale.buyer, price: sale.price }, now);
+        item.seller_proceeds = Some(sale.price - sale.fee);
+    }
+}
+
+fn ledger_canister_id() -> Result<Principal, String> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.ledger_canister_id.ok_or_else(|| "Ledger canister is not configured.".to_string())
+    })
+}
+
+async fn ledger_fee(ledger: Principal) -> Result<u64, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ()).await
+        .map_err(|(code, message)| format!("Ledger fee query failed: {:?} {}", code, message))?;
+    let fee = u64::try_from(&fee.0).map_err(|_| "Ledger fee does not fit in u64.".to_string())?;
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        state.last_ledger_fee = Some(fee);
+    });
+    Ok(fee)
+}
+
+// Send `amount` from the canister's ledger account to `to`, paying `fee` on top
+async fn ledger_transfer(ledger: Principal, to: Principal, amount: u64, fee: u64) -> Result<(), String> {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account { owner: to, subaccount: None },
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,)).await
+        .map_err(|(code, message)| format!("Ledger transfer failed: {:?} {}", code, message))?;
+    result.map(|_| ()).map_err(|e| format!("Ledger transfer failed: {:?}", e))
+}
+
+// Close an auction. A sale pays the seller out of the winner's deposit (less the ledger fee),
+// and the item is only marked sold after the ledger transfer succeeded.
+async fn settle_auction(item_id: u64) -> Result<StopOutcome, String> {
+    let ledger = STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+
+        let item = state.items.get(&item_id)
+            .ok_or_else(|| "Item not found.".to_string())?;
+        if !item.active {
+            return Err("Listing is already stopped.".to_string());
+        }
+        if is_settling(item_id) {
+            return Err("Settlement for this item is already in progress.".to_string());
+        }
+        Ok(state.ledger_canister_id)
+    })?;
+
+    // bids, withdrawals and cancellations on this item are rejected until the guard drops
+    let _guard = SettlementGuard::new(item_id);
+    // without a ledger nobody can have deposited, so no bid is covered and the item goes unsold
+    let fee = match ledger {
+        Some(ledger) => ledger_fee(ledger).await?,
+        None => 0,
+    };
+
+    let pending = STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        begin_settlement(&mut state, item_id, fee, now())
+    })?;
+    let Some(sale) = pending else {
+        return Ok(StopOutcome::Unsold);
+    };
+
+    let ledger = ledger.ok_or_else(|| "Ledger canister is not configured.".to_string())?;
+    // a failed payout leaves the item active and the buyer's deposit untouched
+    ledger_transfer(ledger, sale.seller, sale.price - sale.fee, sale.fee).await?;
+
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        complete_sale(&mut state, item_id, &sale, now());
+    });
+    Ok(StopOutcome::Sold { buyer: sale.buyer, price: sale.price })
+}
+
+
+// Point the canister at a ledger. Once tokens are escrowed they live on the current ledger,
+// so switching is refused while any deposit or bid on a live auction exists.
+fn set_ledger(state: &mut CanisterState, ledger: Principal) -> Result<(), String> {
+    if state.ledger_canister_id.is_some_and(|current| current != ledger) {
+        let has_deposits = state.deposits.values().any(|balance| *balance > 0);
+        let has_live_bids = state.items.values()
+            .filter(|item| item.active)
+            .any(|item| state.item_bids.get(&item.id).is_some_and(|bids| !bids.is_empty()));
+        if has_deposits || has_live_bids {
+            return Err("Cannot change the ledger while deposits or live bids exist.".to_string());
+        }
+    }
+    state.ledger_canister_id = Some(ledger);
+    Ok(())
 }
 
+#[init]
+fn init(args: InitArgs) {
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        state.deployed_at = now();
+        state.ledger_canister_id = Some(args.ledger_canister_id);
+    });
+}
 
 #[pre_upgrade]
 fn pre_upgrade() {
@@ -65,26 +774,28 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<InitArgs>) {
     STATE.with(|state_mutex| {
         let mut state = state_mutex.lock().unwrap();
-        match storage::stable_restore::<(CanisterState,)>() {
+        match storage::stable_restore::<(StableState,)>() {
             Ok((restored_state,)) => {
-                *state = restored_state;
+                *state = restored_state.into();
             },
             Err(e) => {
-                if format!("{}", e).contains("stable memory is empty") || format!("{}", e).contains("empty_stream") {
+                if e.to_string().contains("stable memory is empty") || e.to_string().contains("empty_stream") {
                     ic_cdk::println!("Stable memory empty or malformed, initializing new state.");
-                    *state = CanisterState {
-                        items: BTreeMap::new(),
-                        item_bids: BTreeMap::new(),
-                        next_item_id: 0,
-                    };
+                    // the install time is unknown here, so deployed_at stays 0 rather than the upgrade time
+                    *state = CanisterState::new();
                 } else {
                     ic_cdk::trap(&format!("Failed to decode state from stable memory: {}", e));
                 }
             }
         };
+        if let Some(args) = args {
+            if let Err(e) = set_ledger(&mut state, args.ledger_canister_id) {
+                ic_cdk::trap(&e);
+            }
+        }
     });
 }
 
@@ -96,26 +807,48 @@ fn list_item(name: String, description: String) -> u64 {
     let caller = get_caller();
     STATE.with(|state_mutex| {
         let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+        if state.config.listings_paused {
+            trap("Listing new items is currently paused.");
+        }
+        create_item(&mut state, caller, name, description, None)
+    })
+}
 
-        let item_id = state.next_item_id;
-        state.next_item_id += 1;
-
-        let new_item = Item {
-            id: item_id,
-            owner: caller,
-            name,
-            description,
-            current_highest_bid: 0,
-            highest_bidder: None,
-            active: true,
-            new_owner: None,
-        };
-
-        state.items.insert(item_id, new_item);
-        state.item_bids.insert(item_id, BTreeMap::new()); 
+// List an item whose auction ends automatically after duration_seconds
+#[update]
+fn list_item_timed(name: String, description: String, duration_seconds: u64) -> u64 {
+    let caller = get_caller();
+    if duration_seconds == 0 {
+        trap("Auction duration must be greater than zero.");
+    }
+    let end_time = now().saturating_add(duration_seconds.saturating_mul(1_000_000_000));
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+        if state.config.listings_paused {
+            trap("Listing new items is currently paused.");
+        }
+        create_item(&mut state, caller, name, description, Some(end_time))
+    })
+}
 
-        ic_cdk::println!("Item listed: {} by {}", item_id, caller);
-        item_id
+// List several items in one call, returning their ids in order
+#[update]
+fn list_items(items: Vec<(String, String)>) -> Vec<u64> {
+    let caller = get_caller();
+    if items.len() > MAX_BATCH_LISTING {
+        trap(&format!("Cannot list more than {} items in one batch.", MAX_BATCH_LISTING));
+    }
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+        if state.config.listings_paused {
+            trap("Listing new items is currently paused.");
+        }
+        items.into_iter()
+            .map(|(name, description)| create_item(&mut state, caller, name, description, None))
+            .collect()
     })
 }
 
@@ -126,46 +859,127 @@ fn bid_for_item(item_id: u64, amount: u64) -> Result<String, String> {
 
     STATE.with(|state_mutex| {
         let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+        place_bid(&mut state, item_id, caller, amount, now())?;
+
+        ic_cdk::println!("Bid placed: {} for item {} by {}", amount, item_id, caller);
+        Ok("Bid placed successfully.".to_string())
+    })
+}
+
+// Withdraw the caller's bid from a live auction, recomputing the leader if needed
+#[update]
+fn withdraw_bid(item_id: u64) -> Result<String, String> {
+    let caller = get_caller();
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
 
-        let mut item = state.items.get(&item_id)
-            .ok_or_else(|| "Item not found.".to_string())?
-            .clone(); 
+        let item = state.items.get(&item_id)
+            .ok_or_else(|| "Item not found.".to_string())?;
 
         if !item.active {
             return Err("Auction for this item is no longer active.".to_string());
         }
-        if item.owner == caller {
-            return Err("Cannot bid on your own item.".to_string());
+        if is_expired(item, now()) {
+            return Err("Auction has ended.".to_string());
         }
-        if amount <= item.current_highest_bid {
-            return Err(format!("Bid amount ({}) must be higher than the current highest bid ({}).", amount, item.current_highest_bid));
+        if is_settling(item_id) {
+            return Err("Settlement for this item is in progress.".to_string());
         }
 
-        //  Modify the cloned 'item'.
-        item.current_highest_bid = amount;
-        item.highest_bidder = Some(caller);
+        remove_bid(&mut state, item_id, caller)?;
+
+        ic_cdk::println!("Bid withdrawn for item {} by {}", item_id, caller);
+        Ok("Bid withdrawn successfully.".to_string())
+    })
+}
+
+// Pull `amount` tokens from the caller's ledger account into escrow.
+// The caller must first approve this canister for amount plus the ledger fee (ICRC-2).
+#[update]
+async fn deposit(amount: u64) -> Result<u64, String> {
+    let caller = get_caller();
+    if amount == 0 {
+        return Err("Deposit amount must be greater than zero.".to_string());
+    }
+    let ledger = ledger_canister_id()?;
 
-        // Update the original item in the BTreeMap with the modified clone.
-        state.items.insert(item_id, item); 
+    let arg = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account { owner: caller, subaccount: None },
+        to: Account { owner: ic_cdk::id(), subaccount: None },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(ledger, "icrc2_transfer_from", (arg,)).await
+        .map_err(|(code, message)| format!("Ledger transfer failed: {:?} {}", code, message))?;
+    result.map_err(|e| format!("Ledger transfer failed: {:?}", e))?;
 
-        let item_bids_map = state.item_bids.entry(item_id).or_insert_with(BTreeMap::new);
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
 
-        let new_bid = Bid {
-            bidder: caller,
-            amount,
-        };
-        item_bids_map.insert(caller, new_bid); 
+        let balance = state.deposits.entry(caller).or_default();
+        *balance = balance.saturating_add(amount);
 
-        ic_cdk::println!("Bid placed: {} for item {} by {}", amount, item_id, caller);
-        Ok("Bid placed successfully.".to_string())
+        ic_cdk::println!("Deposit of {} by {}", amount, caller);
+        Ok(*balance)
+    })
+}
+
+// Send unlocked deposit back to the caller's ledger account, less the ledger fee.
+// Returns the remaining deposit.
+#[update]
+async fn withdraw(amount: u64) -> Result<u64, String> {
+    let caller = get_caller();
+    let ledger = ledger_canister_id()?;
+    let fee = ledger_fee(ledger).await?;
+    if amount <= fee {
+        return Err(format!("Withdrawal amount must exceed the ledger fee ({}).", fee));
+    }
+
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+
+        let available = available_balance(&state, caller);
+        if amount > available {
+            return Err(format!("Insufficient available balance: {} available.", available));
+        }
+        // debit before calling the ledger so the same funds cannot be withdrawn twice meanwhile
+        let balance = state.deposits.entry(caller).or_default();
+        *balance -= amount;
+        Ok(())
+    })?;
+
+    let transfer = ledger_transfer(ledger, caller, amount - fee, fee).await;
+
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        let balance = state.deposits.entry(caller).or_default();
+        if let Err(e) = transfer {
+            *balance = balance.saturating_add(amount);
+            return Err(e);
+        }
+
+        ic_cdk::println!("Withdrawal of {} by {}", amount, caller);
+        Ok(*balance)
     })
 }
+
 // 3. Update the listing of an item
 #[update]
 fn update_listing(item_id: u64, new_name: Option<String>, new_description: Option<String>) -> Result<String, String> {
     let caller = get_caller();
     STATE.with(|state_mutex| {
         let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+
+        let has_bids = state.item_bids.get(&item_id).is_some_and(|bids| !bids.is_empty());
+        let config = state.config.clone();
 
         let item = state.items.get_mut(&item_id)
             .ok_or_else(|| "Item not found.".to_string())?;
@@ -177,6 +991,12 @@ fn update_listing(item_id: u64, new_name: Option<String>, new_description: Optio
         if !item.active {
             return Err("Cannot update a listing that is no longer active.".to_string());
         }
+        if has_bids && new_name.is_some() && !config.allow_name_edit_with_bids {
+            return Err("Cannot change the name of an item that already has bids.".to_string());
+        }
+        if has_bids && new_description.is_some() && !config.allow_description_edit_with_bids {
+            return Err("Cannot change the description of an item that already has bids.".to_string());
+        }
 
         if let Some(name) = new_name {
             item.name = name;
@@ -190,51 +1010,333 @@ fn update_listing(item_id: u64, new_name: Option<String>, new_description: Optio
     })
 }
 
-// 4. Stop the listing of an item
+// Set or clear the opening bid of an item before anyone has bid on it
 #[update]
-fn stop_listing(item_id: u64) -> Result<String, String> {
+fn set_opening_bid(item_id: u64, opening_bid: Option<u64>) -> Result<String, String> {
     let caller = get_caller();
     STATE.with(|state_mutex| {
         let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
 
         let item = state.items.get_mut(&item_id)
             .ok_or_else(|| "Item not found.".to_string())?;
 
-        //  Only the owner can stop
         if item.owner != caller {
-            return Err("Only the owner can stop this listing.".to_string());
+            return Err("Only the owner can set the opening bid.".to_string());
         }
         if !item.active {
-            return Err("Listing is already stopped.".to_string());
+            return Err("Cannot update a listing that is no longer active.".to_string());
+        }
+        if item.highest_bidder.is_some() {
+            return Err("Cannot change the opening bid once bidding has started.".to_string());
         }
 
-        item.active = false; 
-        item.new_owner = item.highest_bidder; 
+        item.opening_bid = opening_bid;
 
-        ic_cdk::println!("Listing stopped for item: {} by {}", item_id, caller);
-        Ok("Listing stopped successfully. Highest bidder is now the owner.".to_string())
+        ic_cdk::println!("Opening bid for item {} set to {:?} by {}", item_id, opening_bid, caller);
+        Ok("Opening bid updated successfully.".to_string())
     })
 }
 
-
-// Retrieve a specific item
-#[query]
-fn get_item(item_id: u64) -> Option<Item> {
+// Set or clear the minimum bidder account age of an item
+#[update]
+fn set_min_account_age(item_id: u64, min_account_age_secs: Option<u64>) -> Result<String, String> {
+    let caller = get_caller();
     STATE.with(|state_mutex| {
-        let state = state_mutex.lock().unwrap();
-        state.items.get(&item_id).cloned()
-    })
-}
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
 
-// Retrieve a list of items
-#[query]
-fn list_all_items() -> Vec<Item> {
+        let item = state.items.get_mut(&item_id)
+            .ok_or_else(|| "Item not found.".to_string())?;
+
+        if item.owner != caller {
+            return Err("Only the owner can set the minimum account age.".to_string());
+        }
+        if !item.active {
+            return Err("Cannot update a listing that is no longer active.".to_string());
+        }
+
+        item.min_account_age_secs = min_account_age_secs;
+
+        ic_cdk::println!("Min account age for item {} set to {:?} by {}", item_id, min_account_age_secs, caller);
+        Ok("Minimum account age updated successfully.".to_string())
+    })
+}
+
+// Restrict who may win an item, or lift the restriction with None
+#[update]
+fn set_allowed_buyers(item_id: u64, allowed_buyers: Option<BTreeSet<Principal>>) -> Result<String, String> {
+    let caller = get_caller();
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+
+        let has_bids = state.item_bids.get(&item_id).is_some_and(|bids| !bids.is_empty());
+
+        let item = state.items.get_mut(&item_id)
+            .ok_or_else(|| "Item not found.".to_string())?;
+
+        if item.owner != caller {
+            return Err("Only the owner can set the allowed buyers.".to_string());
+        }
+        if !item.active {
+            return Err("Cannot update a listing that is no longer active.".to_string());
+        }
+        // once bidding has started the owner may only let more buyers in, never pick who wins
+        let only_widens = match (&item.allowed_buyers, &allowed_buyers) {
+            (_, None) => true,
+            (Some(current), Some(new)) => new.is_superset(current),
+            (None, Some(_)) => false,
+        };
+        if has_bids && !only_widens {
+            return Err("Once bidding has started, allowed buyers can only be added.".to_string());
+        }
+
+        item.allowed_buyers = allowed_buyers;
+
+        ic_cdk::println!("Allowed buyers for item {} updated by {}", item_id, caller);
+        Ok("Allowed buyers updated successfully.".to_string())
+    })
+}
+
+// 4. Stop the listing of an item
+#[update]
+async fn stop_listing(item_id: u64) -> Result<String, String> {
+    let caller = get_caller();
+    let previous_leader = STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+
+        let item = state.items.get(&item_id)
+            .ok_or_else(|| "Item not found.".to_string())?;
+
+        //  Only the owner can stop
+        if item.owner != caller {
+            return Err("Only the owner can stop this listing.".to_string());
+        }
+        if !item.active {
+            return Err("Listing is already stopped.".to_string());
+        }
+        Ok(item.highest_bidder)
+    })?;
+
+    let outcome = settle_auction(item_id).await?;
+
+    ic_cdk::println!("Listing stopped for item: {} by {}", item_id, caller);
+    match outcome {
+        StopOutcome::Sold { buyer, .. } if previous_leader == Some(buyer) => {
+            Ok("Listing stopped successfully. Highest bidder is now the owner.".to_string())
+        },
+        StopOutcome::Sold { .. } => {
+            Ok("Listing stopped successfully. Highest eligible bidder is now the owner.".to_string())
+        },
+        StopOutcome::Unsold => {
+            Ok("Listing stopped successfully. Item went unsold.".to_string())
+        },
+    }
+}
+
+// Close every timed auction whose deadline has passed, returning how many were closed.
+// Anyone may call this, so it can be driven by a timer or an external heartbeat.
+#[update]
+async fn finalize_expired_auctions() -> u64 {
+    let now = now();
+    let expired: Vec<u64> = STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.items.values()
+            .filter(|item| item.active && is_expired(item, now))
+            .map(|item| item.id)
+            .collect()
+    });
+
+    let mut finalized = 0;
+    for item_id in expired {
+        match settle_auction(item_id).await {
+            Ok(_) => {
+                finalized += 1;
+                ic_cdk::println!("Auction for item {} finalized after its deadline", item_id);
+            },
+            Err(e) => ic_cdk::println!("Failed to finalize auction for item {}: {}", item_id, e),
+        }
+    }
+    finalized
+}
+
+// Cancel any live auction without a sale, e.g. for a policy violation (admin only)
+#[update]
+fn admin_cancel_auction(item_id: u64, reason: String) -> Result<String, String> {
+    require_admin()?;
+    if reason.trim().is_empty() {
+        return Err("A cancellation reason is required.".to_string());
+    }
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+
+        let item = state.items.get_mut(&item_id)
+            .ok_or_else(|| "Item not found.".to_string())?;
+
+        if !item.active {
+            return Err("Auction for this item is no longer active.".to_string());
+        }
+        if is_settling(item_id) {
+            return Err("Settlement for this item is in progress.".to_string());
+        }
+
+        // closing the auction releases every bidder's locked deposit
+        item.active = false;
+        item.new_owner = None;
+        item.closed_at = Some(now());
+        item.cancel_reason = Some(reason.clone());
+
+        ic_cdk::println!("Auction for item {} cancelled by admin {}: {}", item_id, get_caller(), reason);
+        Ok("Auction cancelled successfully.".to_string())
+    })
+}
+
+// Hand every active, bid-free listing of the caller over to another principal
+#[update]
+fn transfer_all_listings(to: Principal) -> Result<u64, String> {
+    let caller = get_caller();
+    if to == caller {
+        return Err("Cannot transfer listings to yourself.".to_string());
+    }
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        touch_first_seen(&mut state, caller);
+
+        let transferable: Vec<u64> = state.items.values()
+            .filter(|item| item.owner == caller && item.active)
+            .filter(|item| state.item_bids.get(&item.id).is_none_or(|bids| bids.is_empty()))
+            .map(|item| item.id)
+            .collect();
+
+        for item_id in &transferable {
+            if let Some(item) = state.items.get_mut(item_id) {
+                item.owner = to;
+            }
+        }
+
+        ic_cdk::println!("{} listings transferred from {} to {}", transferable.len(), caller, to);
+        Ok(transferable.len() as u64)
+    })
+}
+
+// Change the minimum bid increment of a live auction (admin only)
+#[update]
+fn admin_set_min_increment(item_id: u64, new_increment: u64) -> Result<String, String> {
+    require_admin()?;
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+
+        let item = state.items.get_mut(&item_id)
+            .ok_or_else(|| "Item not found.".to_string())?;
+
+        if !item.active {
+            return Err("Auction for this item is no longer active.".to_string());
+        }
+
+        // only future bids are checked against the new increment
+        item.min_increment = Some(new_increment);
+
+        ic_cdk::println!("Min increment for item {} set to {} by {}", item_id, new_increment, get_caller());
+        Ok("Minimum increment updated successfully.".to_string())
+    })
+}
+
+// Preview what stop_listing would do right now, using the last fee seen from the ledger,
+// without closing the auction
+#[query]
+fn preview_settlement(item_id: u64) -> Option<StopOutcome> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let fee = state.last_ledger_fee.unwrap_or(0);
+        state.items.get(&item_id)
+            .map(|item| settlement_outcome(&state, item, fee))
+    })
+}
+
+
+// Retrieve a specific item
+#[query]
+fn get_item(item_id: u64) -> Option<Item> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.items.get(&item_id).cloned()
+    })
+}
+
+// Retrieve a list of items
+#[query]
+fn list_all_items() -> Vec<Item> {
     STATE.with(|state_mutex| {
         let state = state_mutex.lock().unwrap();
         state.items.values().cloned().collect()
     })
 }
 
+// Check whether the caller could bid on an item right now, with the reason if not
+#[query]
+fn biddability(item_id: u64) -> Biddability {
+    let caller = get_caller();
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let result = match state.items.get(&item_id) {
+            Some(item) => check_can_bid(&state, item, caller, now()),
+            None => Err("Item not found.".to_string()),
+        };
+        match result {
+            Ok(()) => Biddability::Biddable,
+            Err(reason) => Biddability::NotBiddable { reason },
+        }
+    })
+}
+
+// Check whether a principal owns an item, None for unknown items
+#[query]
+fn is_item_owner(item_id: u64, principal: Principal) -> Option<bool> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.items.get(&item_id).map(|item| item.owner == principal)
+    })
+}
+
+// Retrieve a page of items, optionally only active (Some(true)) or closed (Some(false)) ones
+#[query]
+fn list_items_paged(offset: u64, limit: u64, only_active: Option<bool>) -> ItemPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let matches = |item: &&Item| only_active.is_none_or(|active| item.active == active);
+
+        let total = state.items.values().filter(matches).count() as u64;
+        let items: Vec<Item> = state.items.values()
+            .filter(matches)
+            .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+
+        let end = offset.saturating_add(items.len() as u64);
+        ItemPage {
+            items,
+            total,
+            next_offset: if end < total { Some(end) } else { None },
+        }
+    })
+}
+
+// Retrieve items whose name matches exactly (case-sensitive)
+#[query]
+fn get_items_by_exact_name(name: String) -> Vec<Item> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.items.values()
+            .filter(|item| item.name == name)
+            .cloned()
+            .collect()
+    })
+}
+
 // Retrieve the length of items listed on the contract
 #[query]
 fn get_listed_items_count() -> u64 {
@@ -250,12 +1352,127 @@ fn get_most_expensive_sold_item() -> Option<Item> {
     STATE.with(|state_mutex| {
         let state = state_mutex.lock().unwrap();
         state.items.values()
-            .filter(|item| !item.active && item.new_owner.is_some())
+            .filter(|item| is_sold(item))
             .max_by_key(|item| item.current_highest_bid)
             .cloned()
     })
 }
 
+// Retrieve active items with the highest current bids, ties broken by id
+#[query]
+fn get_top_active_by_bid(limit: u64) -> Vec<Item> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let mut active: Vec<&Item> = state.items.values()
+            .filter(|item| item.active)
+            .collect();
+
+        active.sort_by(|a, b| b.current_highest_bid.cmp(&a.current_highest_bid).then(a.id.cmp(&b.id)));
+        active.into_iter()
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    })
+}
+
+// Count the active auctions a user is currently leading
+#[query]
+fn get_leading_count(user: Principal) -> u64 {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        leading_count(&state, user)
+    })
+}
+
+// Retrieve the mean winning price over all sales, truncated towards zero
+#[query]
+fn get_average_sale_price() -> Option<u64> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let prices = sale_prices(&state);
+        if prices.is_empty() {
+            return None;
+        }
+        let total: u128 = prices.iter().map(|&price| price as u128).sum();
+        Some((total / prices.len() as u128) as u64)
+    })
+}
+
+// Retrieve the median winning price over all sales, truncated towards zero
+#[query]
+fn get_median_sale_price() -> Option<u64> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let mut prices = sale_prices(&state);
+        if prices.is_empty() {
+            return None;
+        }
+        prices.sort_unstable();
+
+        let mid = prices.len() / 2;
+        if prices.len() % 2 == 1 {
+            Some(prices[mid])
+        } else {
+            Some(((prices[mid - 1] as u128 + prices[mid] as u128) / 2) as u64)
+        }
+    })
+}
+
+// Retrieve every item a seller has sold, paired with its buyer
+#[query]
+fn get_seller_sales(seller: Principal) -> Vec<(Item, Principal)> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.items.values()
+            .filter(|item| item.owner == seller)
+            .filter_map(|item| match item.new_owner {
+                Some(buyer) if !item.active => Some((item.clone(), buyer)),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+// Summarise what a user has bought, sold and listed
+#[query]
+fn get_user_summary(user: Principal) -> UserSummary {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let mut summary = UserSummary::default();
+
+        for item in state.items.values() {
+            if item.owner == user {
+                summary.items_listed += 1;
+                if is_sold(item) {
+                    // net of the ledger fee; sales from before escrow only have the winning bid
+                    let earned = item.seller_proceeds.unwrap_or(item.current_highest_bid);
+                    summary.total_earned = summary.total_earned.saturating_add(earned);
+                }
+            }
+            if is_sold(item) && item.new_owner == Some(user) {
+                summary.items_won += 1;
+                summary.total_spent = summary.total_spent.saturating_add(item.current_highest_bid);
+            }
+        }
+        summary.locked_escrow = locked_balance(&state, user);
+        summary.available_balance = available_balance(&state, user);
+        summary
+    })
+}
+
+// Retrieve the distinct principals that have won at least one auction
+#[query]
+fn get_all_winners() -> Vec<Principal> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let winners: BTreeSet<Principal> = state.items.values()
+            .filter(|item| is_sold(item))
+            .filter_map(|item| item.new_owner)
+            .collect();
+        winners.into_iter().collect()
+    })
+}
+
 // Retrieve the item that has been bid on the most
 #[query]
 fn get_item_with_most_bids() -> Option<Item> {
@@ -277,6 +1494,33 @@ fn get_item_with_most_bids() -> Option<Item> {
     })
 }
 
+// Retrieve items ranked by number of distinct bidders, ties broken by total bid value then id
+#[query]
+fn list_items_by_bid_count(descending: bool, limit: u64) -> Vec<Item> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let mut ranked: Vec<(u64, u128, &Item)> = state.items.values()
+            .map(|item| {
+                let bids = state.item_bids.get(&item.id);
+                let count = bids.map_or(0, |bids| bids.len() as u64);
+                let total = bids.map_or(0, |bids| bids.values().map(|bid| bid.amount as u128).sum());
+                (count, total, item)
+            })
+            .collect();
+
+        ranked.sort_by(|a, b| {
+            let by_bids = (a.0, a.1).cmp(&(b.0, b.1));
+            let by_bids = if descending { by_bids.reverse() } else { by_bids };
+            by_bids.then(a.2.id.cmp(&b.2.id))
+        });
+
+        ranked.into_iter()
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .map(|(_, _, item)| item.clone())
+            .collect()
+    })
+}
+
 // Get all bids for specific item
 #[query]
 fn get_bids_for_item(item_id: u64) -> Vec<Bid> {
@@ -288,6 +1532,78 @@ fn get_bids_for_item(item_id: u64) -> Vec<Bid> {
     })
 }
 
+// Get the bids placed on an item between two timestamps (inclusive), oldest first
+#[query]
+fn get_bids_in_window(item_id: u64, start_ns: u64, end_ns: u64) -> Result<Vec<Bid>, String> {
+    if start_ns > end_ns {
+        return Err("Window start must not be after its end.".to_string());
+    }
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        Ok(state.bid_log.get(&item_id)
+            .map(|log| log.iter()
+                .filter(|bid| bid.timestamp.is_some_and(|at| at >= start_ns && at <= end_ns))
+                .cloned()
+                .collect())
+            .unwrap_or_default())
+    })
+}
+
+// Get a page of an item's bid log, oldest first
+#[query]
+fn get_bid_log_paged(item_id: u64, offset: u64, limit: u64) -> Vec<Bid> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.bid_log.get(&item_id)
+            .map(|log| log.iter()
+                .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+                .take(usize::try_from(limit.min(MAX_PAGE_SIZE)).unwrap_or(usize::MAX))
+                .cloned()
+                .collect())
+            .unwrap_or_default()
+    })
+}
+
+// Count the bids placed on an item within the last window_secs seconds
+#[query]
+fn get_bid_velocity(item_id: u64, window_secs: u64) -> u64 {
+    let since = now().saturating_sub(window_secs.saturating_mul(1_000_000_000));
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.bid_log.get(&item_id)
+            .map_or(0, |log| log.iter().filter(|bid| bid.timestamp.is_some_and(|at| at >= since)).count() as u64)
+    })
+}
+
+// Get an item's listing, bids and close as one chronological list
+#[query]
+fn get_item_timeline(item_id: u64) -> Vec<TimelineEvent> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let Some(item) = state.items.get(&item_id) else {
+            return Vec::new();
+        };
+
+        let mut events: Vec<(u64, TimelineEvent)> = Vec::new();
+        if let Some(at) = item.listed_at {
+            events.push((at, TimelineEvent::Listed { at, owner: item.owner }));
+        }
+        // bids recorded before timestamps were kept sort right after the listing, in log order
+        let untimed_at = item.listed_at.unwrap_or(0);
+        for bid in state.bid_log.get(&item_id).into_iter().flatten() {
+            events.push((bid.timestamp.unwrap_or(untimed_at), TimelineEvent::BidPlaced { at: bid.timestamp, bidder: bid.bidder, amount: bid.amount }));
+        }
+        if let Some(at) = item.closed_at {
+            let price = if item.new_owner.is_some() { item.current_highest_bid } else { 0 };
+            events.push((at, TimelineEvent::Closed { at, new_owner: item.new_owner, price, cancel_reason: item.cancel_reason.clone() }));
+        }
+
+        // stable sort keeps listing before bids and bids before the close on equal timestamps
+        events.sort_by_key(|(at, _)| *at);
+        events.into_iter().map(|(_, event)| event).collect()
+    })
+}
+
 // get highest bid for specific item
 #[query]
 fn get_highest_bid_for_item(item_id: u64) -> Option<Bid> {
@@ -303,5 +1619,1721 @@ fn get_highest_bid_for_item(item_id: u64) -> Option<Bid> {
     })
 }
 
-// generate The candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+// Get the gap between the highest and second-highest bid for an item
+#[query]
+fn get_bid_spread(item_id: u64) -> Option<u64> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let bids_map = state.item_bids.get(&item_id)?;
+
+        let mut amounts: Vec<u64> = bids_map.values().map(|bid| bid.amount).collect();
+        if amounts.len() < 2 {
+            return None;
+        }
+        amounts.sort_unstable_by(|a, b| b.cmp(a));
+        amounts[0].checked_sub(amounts[1])
+    })
+}
+
+// Get the leading bid of each active auction, largest first
+#[query]
+fn get_current_top_bids(limit: u64) -> Vec<(u64, Bid)> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let mut top_bids: Vec<(u64, Bid)> = state.items.values()
+            .filter(|item| item.active)
+            .filter_map(|item| {
+                let bidder = item.highest_bidder?;
+                let bid = state.item_bids.get(&item.id)?.get(&bidder)?;
+                Some((item.id, bid.clone()))
+            })
+            .collect();
+
+        top_bids.sort_by(|a, b| b.1.amount.cmp(&a.1.amount).then(a.0.cmp(&b.0)));
+        top_bids.truncate(usize::try_from(limit).unwrap_or(usize::MAX));
+        top_bids
+    })
+}
+
+// Get ids of every item a user has ever bid on
+#[query]
+fn get_participation_history(user: Principal) -> Vec<u64> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.participation.get(&user)
+            .map(|item_ids| item_ids.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+// Get the current marketplace config
+#[query]
+fn get_config() -> MarketConfig {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.config.clone()
+    })
+}
+
+// Pause or resume new listings without affecting bids (admin only)
+#[update]
+fn set_listings_paused(paused: bool) -> Result<String, String> {
+    require_admin()?;
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        state.config.listings_paused = paused;
+
+        ic_cdk::println!("Listings paused set to {} by {}", paused, get_caller());
+        Ok("Listings pause updated successfully.".to_string())
+    })
+}
+
+// Find bid entries whose item no longer exists (admin only)
+#[query]
+fn find_orphaned_bids() -> Result<Vec<u64>, String> {
+    require_admin()?;
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        Ok(orphaned_bid_ids(&state))
+    })
+}
+
+// Remove bid entries whose item no longer exists (admin only)
+#[update]
+fn prune_orphaned_bids() -> Result<u64, String> {
+    require_admin()?;
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        let orphaned = orphaned_bid_ids(&state);
+        for item_id in &orphaned {
+            state.item_bids.remove(item_id);
+        }
+
+        ic_cdk::println!("Pruned {} orphaned bid entries by {}", orphaned.len(), get_caller());
+        Ok(orphaned.len() as u64)
+    })
+}
+
+// Assemble the dashboard from the state and the canister's resource metrics
+fn build_dashboard(state: &CanisterState, stable_memory_pages: u64, cycle_balance: u128) -> AdminDashboard {
+    AdminDashboard {
+        config: state.config.clone(),
+        total_items: state.items.len() as u64,
+        active_items: state.items.values().filter(|item| item.active).count() as u64,
+        sold_items: state.items.values().filter(|item| is_sold(item)).count() as u64,
+        total_bids: state.bid_log.values().map(|log| log.len() as u64).sum(),
+        unique_bidders: state.participation.len() as u64,
+        total_deposits: state.deposits.values().fold(0, |total, balance| total.saturating_add(*balance)),
+        stable_memory_pages,
+        cycle_balance,
+        deployed_at: state.deployed_at,
+    }
+}
+
+// Get config, usage counts and resource metrics in one call (admin only)
+#[query]
+fn admin_dashboard() -> Result<AdminDashboard, String> {
+    require_admin()?;
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        Ok(build_dashboard(&state, ic_cdk::api::stable::stable64_size(), ic_cdk::api::canister_balance128()))
+    })
+}
+
+// Candid-encode an item with its bids and bid log for backup or migration
+#[query]
+fn export_item(item_id: u64) -> Option<Vec<u8>> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        let export = ItemExport {
+            item: state.items.get(&item_id)?.clone(),
+            bids: state.item_bids.get(&item_id)
+                .map(|bids_map| bids_map.values().cloned().collect())
+                .unwrap_or_default(),
+            bid_log: state.bid_log.get(&item_id).cloned().unwrap_or_default(),
+        };
+        encode_one(&export).ok()
+    })
+}
+
+// Reject exports whose item, bids and bid log contradict each other
+fn validate_export(export: &ItemExport) -> Result<(), String> {
+    let ItemExport { item, bids, bid_log } = export;
+
+    if item.active {
+        // bids imported onto a live auction would lock deposits that never backed them
+        if !bids.is_empty() {
+            return Err("Invalid item export: an active item can only be imported without bids.".to_string());
+        }
+        if item.new_owner.is_some() || item.closed_at.is_some() || item.cancel_reason.is_some() {
+            return Err("Invalid item export: an active item cannot have a new owner, close time or cancel reason.".to_string());
+        }
+    } else if item.new_owner.is_some() && item.new_owner != item.highest_bidder {
+        return Err("Invalid item export: the new owner is not the highest bidder.".to_string());
+    }
+
+    let bidders: BTreeSet<Principal> = bids.iter().map(|bid| bid.bidder).collect();
+    if bidders.len() != bids.len() {
+        return Err("Invalid item export: more than one bid from the same bidder.".to_string());
+    }
+    if bidders.contains(&item.owner) {
+        return Err("Invalid item export: the owner cannot bid on their own item.".to_string());
+    }
+    // the log also keeps withdrawn and outbid bids, but every standing bid must have been logged
+    let logged = |bid: &Bid| bid_log.iter()
+        .any(|entry| entry.bidder == bid.bidder && entry.amount == bid.amount && entry.timestamp == bid.timestamp);
+    if !bids.iter().all(logged) {
+        return Err("Invalid item export: a bid is missing from the bid log.".to_string());
+    }
+    if let Some(leader) = item.highest_bidder {
+        if bids.iter().find(|bid| bid.bidder == leader).map(|bid| bid.amount) != Some(item.current_highest_bid) {
+            return Err("Invalid item export: highest bid does not match the bids.".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Insert an exported item under a fresh id, returning that id (admin only)
+#[update]
+fn import_item(blob: Vec<u8>) -> Result<u64, String> {
+    require_admin()?;
+    let export: ItemExport = decode_one(&blob)
+        .map_err(|e| format!("Invalid item export: {}", e))?;
+    validate_export(&export)?;
+
+    let ItemExport { mut item, bids, bid_log } = export;
+    let bids_map: BTreeMap<Principal, Bid> = bids.into_iter()
+        .map(|bid| (bid.bidder, bid))
+        .collect();
+
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+
+        let item_id = state.next_item_id;
+        state.next_item_id += 1;
+        item.id = item_id;
+
+        for bid in &bid_log {
+            state.participation.entry(bid.bidder).or_default().insert(item_id);
+        }
+        state.items.insert(item_id, item);
+        state.item_bids.insert(item_id, bids_map);
+        state.bid_log.insert(item_id, bid_log);
+
+        ic_cdk::println!("Item imported as {} by {}", item_id, get_caller());
+        Ok(item_id)
+    })
+}
+
+// Get the time (ns since epoch) the canister was first installed, 0 if that predates tracking it
+#[query]
+fn get_deployed_at() -> u64 {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.deployed_at
+    })
+}
+
+// List the config fields that differ from their defaults, with current values
+#[query]
+fn get_config_overrides() -> Vec<(String, String)> {
+    STATE.with(|state_mutex| {
+        let state = state_mutex.lock().unwrap();
+        state.config.overrides(&MarketConfig::default())
+    })
+}
+
+// Replace the marketplace config (admin only)
+#[update]
+fn set_config(config: MarketConfig) -> Result<String, String> {
+    require_admin()?;
+    STATE.with(|state_mutex| {
+        let mut state = state_mutex.lock().unwrap();
+        state.config = config;
+
+        ic_cdk::println!("Config updated by {}", get_caller());
+        Ok("Config updated successfully.".to_string())
+    })
+}
+
+// generate The candid interface
+ic_cdk::export_candid!();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    // stand-ins for the caller, clock and controller list, one per test thread
+    thread_local! {
+        pub(super) static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        pub(super) static NOW: Cell<u64> = const { Cell::new(0) };
+        pub(super) static ADMINS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    }
+
+    const SEC: u64 = 1_000_000_000;
+
+    fn user(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    fn act_as(who: Principal) {
+        CALLER.with(|caller| caller.set(who));
+    }
+
+    fn set_time(ns: u64) {
+        NOW.with(|now| now.set(ns));
+    }
+
+    fn with_state<R>(f: impl FnOnce(&mut CanisterState) -> R) -> R {
+        STATE.with(|state_mutex| f(&mut state_mutex.lock().unwrap()))
+    }
+
+    fn fund(who: Principal, amount: u64) {
+        with_state(|state| *state.deposits.entry(who).or_default() += amount);
+    }
+
+    // list an item as `owner` and return its id
+    fn listed(owner: Principal) -> u64 {
+        act_as(owner);
+        list_item("Lamp".to_string(), "Brass desk lamp".to_string())
+    }
+
+    fn place_bid_as(bidder: Principal, item_id: u64, amount: u64) -> Result<String, String> {
+        act_as(bidder);
+        bid_for_item(item_id, amount)
+    }
+
+    // push a bid recorded before bids carried a timestamp
+    fn push_untimed_bid(item_id: u64, bidder: Principal, amount: u64) {
+        with_state(|state| state.bid_log.entry(item_id).or_default().push(Bid { bidder, amount, timestamp: None }));
+    }
+
+    // decode saved state the way post_upgrade does
+    fn restore(bytes: &[u8]) -> CanisterState {
+        let (stable,): (StableState,) = candid::decode_args(bytes).unwrap();
+        stable.into()
+    }
+
+    // layout of the state saved by the first release
+    #[derive(CandidType)]
+    struct BaselineItem {
+        id: u64,
+        owner: Principal,
+        name: String,
+        description: String,
+        current_highest_bid: u64,
+        highest_bidder: Option<Principal>,
+        active: bool,
+        new_owner: Option<Principal>,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineBid {
+        bidder: Principal,
+        amount: u64,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineState {
+        items: BTreeMap<u64, BaselineItem>,
+        item_bids: BTreeMap<u64, BTreeMap<Principal, BaselineBid>>,
+        next_item_id: u64,
+    }
+
+    #[test]
+    fn restores_baseline_state() {
+        let item = |id, highest_bidder: Option<Principal>, current_highest_bid| BaselineItem {
+            id,
+            owner: user(1),
+            name: format!("Item {}", id),
+            description: String::new(),
+            current_highest_bid,
+            highest_bidder,
+            active: true,
+            new_owner: None,
+        };
+        let baseline = BaselineState {
+            items: BTreeMap::from([(0, item(0, Some(user(3)), 20)), (1, item(1, Some(user(2)), 5))]),
+            item_bids: BTreeMap::from([
+                (0, BTreeMap::from([(user(2), BaselineBid { bidder: user(2), amount: 10 }), (user(3), BaselineBid { bidder: user(3), amount: 20 })])),
+                (1, BTreeMap::from([(user(2), BaselineBid { bidder: user(2), amount: 5 })])),
+            ]),
+            next_item_id: 2,
+        };
+
+        let state = restore(&candid::encode_args((&baseline,)).unwrap());
+        assert_eq!(state.items.len(), 2);
+        assert_eq!(state.items[&0].current_highest_bid, 20);
+        assert_eq!(state.items[&0].listed_at, None);
+        assert_eq!(state.item_bids[&0][&user(3)].timestamp, None);
+        assert_eq!(state.next_item_id, 2);
+        assert_eq!(state.participation[&user(2)], BTreeSet::from([0, 1]));
+        assert_eq!(state.participation[&user(3)], BTreeSet::from([0]));
+        assert!(state.bid_log.is_empty());
+        assert!(state.deposits.is_empty());
+        assert_eq!(state.config.overrides(&MarketConfig::default()), Vec::new());
+        assert_eq!(state.ledger_canister_id, None);
+        assert_eq!(state.deployed_at, 0);
+    }
+
+    #[test]
+    fn upgrade_keeps_deployed_at() {
+        let mut saved = CanisterState::new();
+        saved.deployed_at = 42 * SEC;
+        set_time(100 * SEC);
+
+        let state = restore(&candid::encode_args((&saved,)).unwrap());
+        assert_eq!(state.deployed_at, 42 * SEC);
+    }
+
+    #[test]
+    fn restores_current_state() {
+        let mut saved = CanisterState::new();
+        let item_id = create_item(&mut saved, user(1), "Lamp".to_string(), String::new(), None);
+        // user(2) has withdrawn their bid, but participation still remembers it
+        saved.participation.insert(user(2), BTreeSet::from([item_id]));
+        saved.first_seen.insert(user(2), 7);
+        saved.config.listings_paused = true;
+        saved.ledger_canister_id = Some(user(9));
+        saved.deposits.insert(user(2), 50);
+
+        let state = restore(&candid::encode_args((&saved,)).unwrap());
+        assert_eq!(state.items.len(), 1);
+        assert_eq!(state.next_item_id, 1);
+        assert_eq!(state.participation[&user(2)], BTreeSet::from([item_id]));
+        assert_eq!(state.first_seen[&user(2)], 7);
+        assert!(state.config.listings_paused);
+        assert_eq!(state.ledger_canister_id, Some(user(9)));
+        assert_eq!(state.deposits[&user(2)], 50);
+    }
+
+    // an item with bids from user(2) for 10 and user(3) for 20, each fully funded
+    fn contested_item() -> u64 {
+        let item_id = listed(user(1));
+        fund(user(2), 10);
+        fund(user(3), 20);
+        place_bid_as(user(2), item_id, 10).unwrap();
+        place_bid_as(user(3), item_id, 20).unwrap();
+        item_id
+    }
+
+    fn settle(item_id: u64, fee: u64) -> Option<StopOutcome> {
+        with_state(|state| {
+            let sale = begin_settlement(state, item_id, fee, now()).unwrap()?;
+            complete_sale(state, item_id, &sale, now());
+            Some(StopOutcome::Sold { buyer: sale.buyer, price: sale.price })
+        })
+    }
+
+    #[test]
+    fn bid_over_unlocked_balance_is_rejected() {
+        let first = listed(user(1));
+        let second = listed(user(1));
+        fund(user(2), 30);
+
+        with_state(|state| {
+            place_bid(state, first, user(2), 20, 0).unwrap();
+            assert_eq!(locked_balance(state, user(2)), 20);
+            assert_eq!(available_balance(state, user(2)), 10);
+            assert!(place_bid(state, second, user(2), 11, 0).is_err());
+            place_bid(state, second, user(2), 10, 0).unwrap();
+            assert_eq!(available_balance(state, user(2)), 0);
+        });
+    }
+
+    #[test]
+    fn raising_own_bid_reuses_its_lock() {
+        let item_id = listed(user(1));
+        fund(user(2), 30);
+
+        with_state(|state| {
+            place_bid(state, item_id, user(2), 20, 0).unwrap();
+            // only the extra 10 comes out of the unlocked balance
+            place_bid(state, item_id, user(2), 30, 0).unwrap();
+            assert_eq!(locked_balance(state, user(2)), 30);
+            assert_eq!(available_balance(state, user(2)), 0);
+            assert!(place_bid(state, item_id, user(2), 31, 0).is_err());
+        });
+    }
+
+    #[test]
+    fn leader_withdrawal_hands_lead_to_runner_up() {
+        let item_id = contested_item();
+        with_state(|state| remove_bid(state, item_id, user(3))).unwrap();
+
+        let item = get_item(item_id).unwrap();
+        assert_eq!(item.highest_bidder, Some(user(2)));
+        assert_eq!(item.current_highest_bid, 10);
+        let highest = get_highest_bid_for_item(item_id).unwrap();
+        assert_eq!((highest.bidder, highest.amount), (user(2), 10));
+        assert_eq!(with_state(|state| locked_balance(state, user(3))), 0);
+    }
+
+    #[test]
+    fn runner_up_withdrawal_keeps_leader() {
+        let item_id = contested_item();
+        with_state(|state| remove_bid(state, item_id, user(2))).unwrap();
+
+        let item = get_item(item_id).unwrap();
+        assert_eq!(item.highest_bidder, Some(user(3)));
+        assert_eq!(item.current_highest_bid, 20);
+        assert_eq!(get_highest_bid_for_item(item_id).unwrap().bidder, user(3));
+    }
+
+    #[test]
+    fn last_withdrawal_resets_item() {
+        let item_id = listed(user(1));
+        fund(user(2), 10);
+        place_bid_as(user(2), item_id, 10).unwrap();
+
+        with_state(|state| remove_bid(state, item_id, user(2))).unwrap();
+        let item = get_item(item_id).unwrap();
+        assert_eq!(item.highest_bidder, None);
+        assert_eq!(item.current_highest_bid, 0);
+        assert!(get_highest_bid_for_item(item_id).is_none());
+        assert!(with_state(|state| remove_bid(state, item_id, user(2))).is_err());
+    }
+
+    #[test]
+    fn failed_payout_leaves_auction_open() {
+        let item_id = contested_item();
+
+        // begin_settlement picks the sale, then the ledger transfer fails and complete_sale never runs
+        let sale = with_state(|state| begin_settlement(state, item_id, 1, 0)).unwrap();
+        assert!(sale.is_some());
+        let item = get_item(item_id).unwrap();
+        assert!(item.active);
+        assert_eq!(item.new_owner, None);
+        assert_eq!(with_state(|state| state.deposits[&user(3)]), 20);
+        assert_eq!(with_state(|state| locked_balance(state, user(3))), 20);
+    }
+
+    #[test]
+    fn successful_payout_charges_buyer_and_closes_auction() {
+        let item_id = contested_item();
+        set_time(5 * SEC);
+
+        assert_eq!(settle(item_id, 1), Some(StopOutcome::Sold { buyer: user(3), price: 20 }));
+        let item = get_item(item_id).unwrap();
+        assert!(!item.active);
+        assert_eq!(item.new_owner, Some(user(3)));
+        assert_eq!(item.closed_at, Some(5 * SEC));
+        with_state(|state| {
+            assert_eq!(state.deposits[&user(3)], 0);
+            // the losing bid is unlocked but stays deposited until withdrawn
+            assert_eq!(state.deposits[&user(2)], 10);
+            assert_eq!(available_balance(state, user(2)), 10);
+        });
+    }
+
+    #[test]
+    fn settlement_skips_uncovered_leader() {
+        let item_id = contested_item();
+        // the leader's deposit no longer covers the bid, e.g. after a sale elsewhere
+        with_state(|state| state.deposits.insert(user(3), 5));
+
+        assert_eq!(settle(item_id, 1), Some(StopOutcome::Sold { buyer: user(2), price: 10 }));
+        let item = get_item(item_id).unwrap();
+        assert_eq!(item.new_owner, Some(user(2)));
+        assert_eq!(item.current_highest_bid, 10);
+    }
+
+    #[test]
+    fn settlement_without_covered_bid_goes_unsold() {
+        let item_id = contested_item();
+        with_state(|state| state.deposits.clear());
+
+        assert_eq!(settle(item_id, 1), None);
+        let item = get_item(item_id).unwrap();
+        assert!(!item.active);
+        assert_eq!(item.new_owner, None);
+    }
+
+    #[test]
+    fn settlement_skips_bids_not_above_fee() {
+        let item_id = contested_item();
+
+        assert_eq!(settle(item_id, 20), None);
+        assert!(!get_item(item_id).unwrap().active);
+        assert_eq!(with_state(|state| state.deposits[&user(3)]), 20);
+    }
+
+    #[test]
+    fn seller_earnings_are_net_of_fee() {
+        let item_id = contested_item();
+        settle(item_id, 3);
+
+        assert_eq!(get_item(item_id).unwrap().seller_proceeds, Some(17));
+        assert_eq!(get_user_summary(user(1)).total_earned, 17);
+        assert_eq!(get_user_summary(user(3)).total_spent, 20);
+    }
+
+    fn act_as_admin() {
+        ADMINS.with(|admins| admins.borrow_mut().insert(user(9)));
+        act_as(user(9));
+    }
+
+    // export of a sold item: user(3) won at 20 over user(2) at 10
+    fn sold_export() -> ItemExport {
+        let item_id = contested_item();
+        settle(item_id, 1);
+        decode_one(&export_item(item_id).unwrap()).unwrap()
+    }
+
+    fn import(export: &ItemExport) -> Result<u64, String> {
+        act_as_admin();
+        import_item(encode_one(export).unwrap())
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let export = sold_export();
+        let original = export.item.id;
+
+        let imported = import(&export).unwrap();
+        assert_ne!(imported, original);
+        let item = get_item(imported).unwrap();
+        assert_eq!(item.id, imported);
+        assert_eq!((item.name.clone(), item.new_owner, item.current_highest_bid), ("Lamp".to_string(), Some(user(3)), 20));
+        assert_eq!(get_bids_for_item(imported).len(), 2);
+        assert_eq!(get_bid_log_paged(imported, 0, 10).len(), 2);
+        assert!(get_participation_history(user(2)).contains(&imported));
+
+        // re-exporting the copy gives back the same content
+        let again: ItemExport = decode_one(&export_item(imported).unwrap()).unwrap();
+        assert_eq!(again.bids.len(), export.bids.len());
+        assert_eq!(again.bid_log.len(), export.bid_log.len());
+        assert_eq!(again.item.seller_proceeds, export.item.seller_proceeds);
+    }
+
+    #[test]
+    fn export_import_round_trip_after_withdrawal() {
+        let item_id = contested_item();
+        fund(user(4), 25);
+        place_bid_as(user(4), item_id, 25).unwrap();
+        act_as(user(4));
+        withdraw_bid(item_id).unwrap();
+        settle(item_id, 1);
+        let export: ItemExport = decode_one(&export_item(item_id).unwrap()).unwrap();
+        assert_eq!((export.bids.len(), export.bid_log.len()), (2, 3));
+
+        let imported = import(&export).unwrap();
+        assert_eq!(get_item(imported).unwrap().new_owner, Some(user(3)));
+        assert_eq!(get_bid_log_paged(imported, 0, 10).len(), 3);
+        // the withdrawn bidder still took part in the auction
+        assert!(get_participation_history(user(4)).contains(&imported));
+    }
+
+    #[test]
+    fn import_requires_admin() {
+        let blob = encode_one(sold_export()).unwrap();
+        act_as(user(2));
+        assert!(import_item(blob).is_err());
+    }
+
+    #[test]
+    fn import_rejects_garbage() {
+        act_as_admin();
+        assert!(import_item(vec![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn import_rejects_active_item_with_bids() {
+        let item_id = contested_item();
+        act_as_admin();
+        assert!(import_item(export_item(item_id).unwrap()).is_err());
+    }
+
+    #[test]
+    fn import_rejects_closed_fields_on_active_item() {
+        let item_id = listed(user(1));
+        let mut export: ItemExport = decode_one(&export_item(item_id).unwrap()).unwrap();
+        assert!(import(&export).is_ok());
+
+        export.item.closed_at = Some(1);
+        assert!(import(&export).is_err());
+        export.item.closed_at = None;
+        export.item.new_owner = Some(user(2));
+        assert!(import(&export).is_err());
+    }
+
+    #[test]
+    fn import_rejects_new_owner_other_than_leader() {
+        let mut export = sold_export();
+        export.item.new_owner = Some(user(2));
+        assert!(import(&export).is_err());
+    }
+
+    #[test]
+    fn import_rejects_bid_missing_from_log() {
+        let mut export = sold_export();
+        export.bids.push(Bid { bidder: user(4), amount: 5, timestamp: None });
+        assert!(import(&export).is_err());
+
+        let mut export = sold_export();
+        export.bid_log.retain(|bid| bid.bidder != user(2));
+        assert!(import(&export).is_err());
+    }
+
+    #[test]
+    fn import_rejects_owner_bids() {
+        let mut export = sold_export();
+        export.bids.push(Bid { bidder: user(1), amount: 5, timestamp: None });
+        assert!(import(&export).is_err());
+    }
+
+    #[test]
+    fn import_rejects_leader_mismatch() {
+        let mut export = sold_export();
+        export.item.current_highest_bid = 15;
+        assert!(import(&export).is_err());
+    }
+
+    fn page_ids(page: &ItemPage) -> Vec<u64> {
+        page.items.iter().map(|item| item.id).collect()
+    }
+
+    #[test]
+    fn paging_walks_all_items() {
+        for _ in 0..5 {
+            listed(user(1));
+        }
+
+        let first = list_items_paged(0, 2, None);
+        assert_eq!((page_ids(&first), first.total, first.next_offset), (vec![0, 1], 5, Some(2)));
+        let second = list_items_paged(2, 2, None);
+        assert_eq!((page_ids(&second), second.next_offset), (vec![2, 3], Some(4)));
+        let last = list_items_paged(4, 2, None);
+        assert_eq!((page_ids(&last), last.next_offset), (vec![4], None));
+    }
+
+    #[test]
+    fn paging_filters_by_activity() {
+        for _ in 0..4 {
+            listed(user(1));
+        }
+        with_state(|state| {
+            for item_id in [1, 2] {
+                close_auction(state.items.get_mut(&item_id).unwrap(), &StopOutcome::Unsold, 0);
+            }
+        });
+
+        let active = list_items_paged(0, 1, Some(true));
+        assert_eq!((page_ids(&active), active.total, active.next_offset), (vec![0], 2, Some(1)));
+        let closed = list_items_paged(0, 10, Some(false));
+        assert_eq!((page_ids(&closed), closed.total, closed.next_offset), (vec![1, 2], 2, None));
+    }
+
+    #[test]
+    fn paging_clamps_limit_and_handles_huge_offset() {
+        for _ in 0..3 {
+            listed(user(1));
+        }
+
+        // a zero limit returns an empty page, and limits above the page size are capped
+        let empty = list_items_paged(0, 0, None);
+        assert!(empty.items.is_empty());
+        assert_eq!((empty.total, empty.next_offset), (3, Some(0)));
+        with_state(|state| {
+            for _ in 0..MAX_PAGE_SIZE {
+                create_item(state, user(1), "Lamp".to_string(), String::new(), None);
+            }
+        });
+        let page = list_items_paged(0, u64::MAX, None);
+        assert_eq!(page.items.len() as u64, MAX_PAGE_SIZE);
+        assert_eq!(page.next_offset, Some(MAX_PAGE_SIZE));
+
+        let past_end = list_items_paged(u64::MAX, 10, None);
+        assert!(past_end.items.is_empty());
+        assert_eq!(past_end.next_offset, None);
+    }
+
+    #[test]
+    fn bid_log_pages_oldest_first() {
+        let item_id = listed(user(1));
+        fund(user(2), 100);
+        for amount in 1..=5 {
+            place_bid_as(user(2), item_id, amount).unwrap();
+        }
+
+        let amounts = |bids: Vec<Bid>| bids.iter().map(|bid| bid.amount).collect::<Vec<_>>();
+        assert_eq!(amounts(get_bid_log_paged(item_id, 0, 2)), vec![1, 2]);
+        assert_eq!(amounts(get_bid_log_paged(item_id, 3, 10)), vec![4, 5]);
+        assert!(get_bid_log_paged(item_id, u64::MAX, 10).is_empty());
+        assert_eq!(get_bid_log_paged(item_id, 0, u64::MAX).len(), 5);
+        assert!(get_bid_log_paged(item_id + 1, 0, 10).is_empty());
+    }
+
+    fn ids(items: &[Item]) -> Vec<u64> {
+        items.iter().map(|item| item.id).collect()
+    }
+
+    // five listings with bids (item: amounts) 0: [10], 1: [5, 6], 2: [3, 4], 3: [], 4: [10]
+    fn ranked_items() {
+        for _ in 0..5 {
+            listed(user(1));
+        }
+        for bidder in 2..=4 {
+            fund(user(bidder), 100);
+        }
+        place_bid_as(user(2), 0, 10).unwrap();
+        place_bid_as(user(2), 1, 5).unwrap();
+        place_bid_as(user(3), 1, 6).unwrap();
+        place_bid_as(user(2), 2, 3).unwrap();
+        place_bid_as(user(3), 2, 4).unwrap();
+        place_bid_as(user(4), 4, 10).unwrap();
+    }
+
+    #[test]
+    fn bid_count_ranking_breaks_ties_by_total_then_id() {
+        ranked_items();
+
+        assert_eq!(ids(&list_items_by_bid_count(true, 10)), vec![1, 2, 0, 4, 3]);
+        assert_eq!(ids(&list_items_by_bid_count(false, 10)), vec![3, 0, 4, 2, 1]);
+        assert_eq!(ids(&list_items_by_bid_count(true, 2)), vec![1, 2]);
+        assert_eq!(list_items_by_bid_count(true, u64::MAX).len(), 5);
+        assert!(list_items_by_bid_count(true, 0).is_empty());
+    }
+
+    #[test]
+    fn top_active_by_bid_skips_closed_and_breaks_ties_by_id() {
+        ranked_items();
+        with_state(|state| close_auction(state.items.get_mut(&1).unwrap(), &StopOutcome::Unsold, 0));
+
+        assert_eq!(ids(&get_top_active_by_bid(10)), vec![0, 4, 2, 3]);
+        assert_eq!(ids(&get_top_active_by_bid(1)), vec![0]);
+        assert_eq!(get_top_active_by_bid(u64::MAX).len(), 4);
+    }
+
+    #[test]
+    fn current_top_bids_are_leaders_by_amount_then_id() {
+        ranked_items();
+
+        let top: Vec<(u64, Principal, u64)> = get_current_top_bids(10).into_iter()
+            .map(|(item_id, bid)| (item_id, bid.bidder, bid.amount))
+            .collect();
+        assert_eq!(top, vec![(0, user(2), 10), (4, user(4), 10), (1, user(3), 6), (2, user(3), 4)]);
+        assert_eq!(get_current_top_bids(2).len(), 2);
+        assert_eq!(get_current_top_bids(u64::MAX).len(), 4);
+    }
+
+    // preview with the ledger's fee, then settle for real and check both agree
+    fn assert_preview_matches_close(item_id: u64, fee: u64) -> StopOutcome {
+        with_state(|state| state.last_ledger_fee = Some(fee));
+        let preview = preview_settlement(item_id).unwrap();
+        let closed = settle(item_id, fee).unwrap_or(StopOutcome::Unsold);
+        assert_eq!(preview, closed);
+        assert_eq!(get_item(item_id).unwrap().new_owner, match closed {
+            StopOutcome::Sold { buyer, .. } => Some(buyer),
+            StopOutcome::Unsold => None,
+        });
+        closed
+    }
+
+    #[test]
+    fn preview_matches_close_without_bids() {
+        let item_id = listed(user(1));
+        assert_eq!(assert_preview_matches_close(item_id, 1), StopOutcome::Unsold);
+    }
+
+    #[test]
+    fn preview_matches_close_for_leader() {
+        let item_id = contested_item();
+        assert_eq!(assert_preview_matches_close(item_id, 1), StopOutcome::Sold { buyer: user(3), price: 20 });
+    }
+
+    #[test]
+    fn preview_matches_close_with_allowlist() {
+        let item_id = contested_item();
+        with_state(|state| state.items.get_mut(&item_id).unwrap().allowed_buyers = Some(BTreeSet::from([user(2)])));
+        assert_eq!(assert_preview_matches_close(item_id, 1), StopOutcome::Sold { buyer: user(2), price: 10 });
+    }
+
+    #[test]
+    fn preview_matches_close_with_uncovered_leader() {
+        let item_id = contested_item();
+        with_state(|state| state.deposits.insert(user(3), 0));
+        assert_eq!(assert_preview_matches_close(item_id, 1), StopOutcome::Sold { buyer: user(2), price: 10 });
+    }
+
+    #[test]
+    fn preview_matches_close_when_fee_exceeds_bids() {
+        let item_id = contested_item();
+        assert_eq!(assert_preview_matches_close(item_id, 20), StopOutcome::Unsold);
+    }
+
+    #[test]
+    fn preview_of_unknown_item_is_none() {
+        assert_eq!(preview_settlement(7), None);
+    }
+
+    #[test]
+    fn allowlist_awards_best_allowed_bid() {
+        let item_id = listed(user(1));
+        act_as(user(2));
+        assert!(set_allowed_buyers(item_id, Some(BTreeSet::from([user(2)]))).is_err());
+        act_as(user(1));
+        set_allowed_buyers(item_id, Some(BTreeSet::from([user(2), user(3)]))).unwrap();
+        for (bidder, amount) in [(2, 10), (3, 20), (4, 30)] {
+            fund(user(bidder), amount);
+            place_bid_as(user(bidder), item_id, amount).unwrap();
+        }
+
+        // user(4) still leads, but only allowed buyers can win
+        with_state(|state| {
+            let item = &state.items[&item_id];
+            assert_eq!(item.highest_bidder, Some(user(4)));
+            assert_eq!(settlement_outcome(state, item, 0), StopOutcome::Sold { buyer: user(3), price: 20 });
+        });
+
+        act_as(user(1));
+        set_allowed_buyers(item_id, None).unwrap();
+        with_state(|state| {
+            assert_eq!(settlement_outcome(state, &state.items[&item_id], 0), StopOutcome::Sold { buyer: user(4), price: 30 });
+        });
+    }
+
+    #[test]
+    fn allowlist_without_allowed_bids_goes_unsold() {
+        let item_id = contested_item();
+        with_state(|state| state.items.get_mut(&item_id).unwrap().allowed_buyers = Some(BTreeSet::from([user(5)])));
+
+        with_state(|state| assert_eq!(settlement_outcome(state, &state.items[&item_id], 0), StopOutcome::Unsold));
+    }
+
+    #[test]
+    fn allowlist_can_only_widen_after_bids() {
+        let item_id = listed(user(1));
+        act_as(user(1));
+        set_allowed_buyers(item_id, Some(BTreeSet::from([user(2), user(3)]))).unwrap();
+        fund(user(2), 10);
+        place_bid_as(user(2), item_id, 10).unwrap();
+
+        act_as(user(1));
+        // dropping the leader, or narrowing in any other way, would let the owner choose the winner
+        assert!(set_allowed_buyers(item_id, Some(BTreeSet::from([user(3)]))).is_err());
+        assert!(set_allowed_buyers(item_id, Some(BTreeSet::new())).is_err());
+        set_allowed_buyers(item_id, Some(BTreeSet::from([user(2), user(3), user(4)]))).unwrap();
+        set_allowed_buyers(item_id, None).unwrap();
+        assert!(set_allowed_buyers(item_id, Some(BTreeSet::from([user(2), user(3), user(4)]))).is_err());
+        assert_eq!(get_item(item_id).unwrap().allowed_buyers, None);
+    }
+
+    #[test]
+    fn equal_allowed_bids_go_to_the_earlier_one() {
+        let item_id = listed(user(1));
+        with_state(|state| {
+            let bids_map = state.item_bids.entry(item_id).or_default();
+            bids_map.insert(user(2), Bid { bidder: user(2), amount: 10, timestamp: Some(5) });
+            bids_map.insert(user(3), Bid { bidder: user(3), amount: 10, timestamp: Some(3) });
+            state.deposits.insert(user(2), 10);
+            state.deposits.insert(user(3), 10);
+            state.items.get_mut(&item_id).unwrap().allowed_buyers = Some(BTreeSet::from([user(2), user(3)]));
+
+            assert_eq!(settlement_outcome(state, &state.items[&item_id], 0), StopOutcome::Sold { buyer: user(3), price: 10 });
+        });
+    }
+
+    #[test]
+    fn config_overrides_list_changed_fields() {
+        let defaults = MarketConfig::default();
+        assert!(defaults.overrides(&defaults).is_empty());
+
+        let config = MarketConfig {
+            allow_name_edit_with_bids: true,
+            max_simultaneous_leads: Some(3),
+            ..MarketConfig::default()
+        };
+        assert_eq!(config.overrides(&defaults), vec![
+            ("allow_name_edit_with_bids".to_string(), "true".to_string()),
+            ("max_simultaneous_leads".to_string(), "3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn config_overrides_show_cleared_limit_as_none() {
+        let defaults = MarketConfig { max_simultaneous_leads: Some(2), ..MarketConfig::default() };
+        assert_eq!(MarketConfig::default().overrides(&defaults), vec![("max_simultaneous_leads".to_string(), "none".to_string())]);
+    }
+
+    #[test]
+    fn config_overrides_follow_set_config() {
+        assert!(get_config_overrides().is_empty());
+
+        act_as(user(2));
+        assert!(set_config(MarketConfig { listings_paused: true, ..MarketConfig::default() }).is_err());
+        act_as_admin();
+        set_config(MarketConfig { listings_paused: true, ..MarketConfig::default() }).unwrap();
+        assert_eq!(get_config_overrides(), vec![("listings_paused".to_string(), "true".to_string())]);
+    }
+
+    // list an item and close it as sold to `buyer` at `price`
+    fn sold_at(price: u64, buyer: Principal) -> u64 {
+        with_state(|state| {
+            let item_id = create_item(state, user(1), "Lamp".to_string(), String::new(), None);
+            close_auction(state.items.get_mut(&item_id).unwrap(), &StopOutcome::Sold { buyer, price }, 0);
+            item_id
+        })
+    }
+
+    // closed unsold, free and still-running items that sale statistics must ignore
+    fn non_sales() {
+        let unsold = listed(user(1));
+        with_state(|state| close_auction(state.items.get_mut(&unsold).unwrap(), &StopOutcome::Unsold, 0));
+        sold_at(0, user(2));
+        let running = listed(user(1));
+        fund(user(3), 1_000);
+        place_bid_as(user(3), running, 1_000).unwrap();
+    }
+
+    #[test]
+    fn average_sale_price_over_sales_only() {
+        assert_eq!(get_average_sale_price(), None);
+        non_sales();
+        assert_eq!(get_average_sale_price(), None);
+
+        sold_at(10, user(2));
+        sold_at(20, user(2));
+        sold_at(25, user(2));
+        // 55 / 3 truncates towards zero
+        assert_eq!(get_average_sale_price(), Some(18));
+    }
+
+    #[test]
+    fn average_sale_price_does_not_overflow() {
+        sold_at(u64::MAX, user(2));
+        sold_at(u64::MAX - 2, user(2));
+        assert_eq!(get_average_sale_price(), Some(u64::MAX - 1));
+    }
+
+    #[test]
+    fn participation_history_outlives_bids() {
+        let first = listed(user(1));
+        let second = listed(user(1));
+        let third = listed(user(1));
+        let untouched = listed(user(1));
+        fund(user(2), 100);
+        fund(user(3), 100);
+        place_bid_as(user(2), third, 5).unwrap();
+        place_bid_as(user(2), first, 5).unwrap();
+        place_bid_as(user(2), first, 6).unwrap();
+        place_bid_as(user(2), second, 5).unwrap();
+        place_bid_as(user(3), second, 7).unwrap();
+
+        // first is won, second is lost at settlement and the bid on third is withdrawn
+        assert_eq!(settle(first, 1), Some(StopOutcome::Sold { buyer: user(2), price: 6 }));
+        assert_eq!(settle(second, 1), Some(StopOutcome::Sold { buyer: user(3), price: 7 }));
+        act_as(user(2));
+        withdraw_bid(third).unwrap();
+
+        assert_eq!(get_participation_history(user(2)), vec![first, second, third]);
+        assert!(!get_participation_history(user(2)).contains(&untouched));
+        assert_eq!(get_participation_history(user(3)), vec![second]);
+        assert!(get_participation_history(user(4)).is_empty());
+    }
+
+    #[test]
+    fn edits_after_bids_follow_config() {
+        let item_id = listed(user(1));
+        act_as(user(1));
+        update_listing(item_id, Some("Desk lamp".to_string()), None).unwrap();
+
+        fund(user(2), 10);
+        place_bid_as(user(2), item_id, 10).unwrap();
+        act_as(user(1));
+        update_listing(item_id, None, Some("Now with bulb".to_string())).unwrap();
+        assert!(update_listing(item_id, Some("Floor lamp".to_string()), None).is_err());
+
+        with_state(|state| {
+            state.config.allow_name_edit_with_bids = true;
+            state.config.allow_description_edit_with_bids = false;
+        });
+        update_listing(item_id, Some("Floor lamp".to_string()), None).unwrap();
+        assert!(update_listing(item_id, None, Some("Bulb not included".to_string())).is_err());
+
+        let item = get_item(item_id).unwrap();
+        assert_eq!((item.name.as_str(), item.description.as_str()), ("Floor lamp", "Now with bulb"));
+    }
+
+    #[test]
+    fn edits_after_bids_for_every_flag_combination() {
+        for (allow_name, allow_description) in [(true, true), (true, false), (false, true), (false, false)] {
+            let item_id = listed(user(1));
+            fund(user(2), 10);
+            place_bid_as(user(2), item_id, 10).unwrap();
+            with_state(|state| {
+                state.config.allow_name_edit_with_bids = allow_name;
+                state.config.allow_description_edit_with_bids = allow_description;
+            });
+
+            act_as(user(1));
+            assert_eq!(update_listing(item_id, Some("Renamed".to_string()), None).is_ok(), allow_name);
+            assert_eq!(update_listing(item_id, None, Some("Redescribed".to_string())).is_ok(), allow_description);
+            // a combined edit needs both flags and is applied all or nothing
+            assert_eq!(update_listing(item_id, Some("Both".to_string()), Some("Both".to_string())).is_ok(), allow_name && allow_description);
+
+            let item = get_item(item_id).unwrap();
+            let expected_name = if allow_name && allow_description { "Both" } else if allow_name { "Renamed" } else { "Lamp" };
+            let expected_description = if allow_name && allow_description { "Both" } else if allow_description { "Redescribed" } else { "Brass desk lamp" };
+            assert_eq!((item.name.as_str(), item.description.as_str()), (expected_name, expected_description));
+        }
+    }
+
+    #[test]
+    fn only_owner_updates_listing() {
+        let item_id = listed(user(1));
+        act_as(user(2));
+        assert!(update_listing(item_id, Some("Mine".to_string()), None).is_err());
+        assert_eq!(get_item(item_id).unwrap().name, "Lamp");
+    }
+
+    fn batch(count: usize) -> Vec<(String, String)> {
+        (0..count).map(|n| (format!("Item {}", n), String::new())).collect()
+    }
+
+    #[test]
+    fn batch_listing_returns_ids_in_order() {
+        listed(user(1));
+        act_as(user(2));
+        assert_eq!(list_items(batch(3)), vec![1, 2, 3]);
+
+        let item = get_item(3).unwrap();
+        assert_eq!((item.owner, item.name.as_str(), item.active), (user(2), "Item 2", true));
+        assert!(list_items(Vec::new()).is_empty());
+        assert_eq!(list_items(batch(MAX_BATCH_LISTING)).len(), MAX_BATCH_LISTING);
+    }
+
+    // natively, ic_cdk::trap panics instead of rejecting the call
+    #[test]
+    #[should_panic(expected = "trap")]
+    fn oversized_batch_is_rejected() {
+        act_as(user(1));
+        list_items(batch(MAX_BATCH_LISTING + 1));
+    }
+
+    #[test]
+    fn bid_spread_is_gap_between_top_two_bids() {
+        let item_id = listed(user(1));
+        assert_eq!(get_bid_spread(item_id), None);
+        fund(user(2), 100);
+        fund(user(3), 100);
+        fund(user(4), 100);
+        place_bid_as(user(2), item_id, 10).unwrap();
+        assert_eq!(get_bid_spread(item_id), None);
+
+        place_bid_as(user(3), item_id, 25).unwrap();
+        assert_eq!(get_bid_spread(item_id), Some(15));
+        place_bid_as(user(4), item_id, 30).unwrap();
+        assert_eq!(get_bid_spread(item_id), Some(5));
+        assert_eq!(get_bid_spread(item_id + 1), None);
+    }
+
+    #[test]
+    fn min_increment_applies_to_later_bids() {
+        let item_id = listed(user(1));
+        fund(user(2), 100);
+        fund(user(3), 100);
+        place_bid_as(user(2), item_id, 10).unwrap();
+
+        act_as(user(1));
+        assert!(admin_set_min_increment(item_id, 5).is_err());
+        act_as_admin();
+        admin_set_min_increment(item_id, 5).unwrap();
+        assert_eq!(get_item(item_id).unwrap().min_increment, Some(5));
+
+        assert!(place_bid_as(user(3), item_id, 14).is_err());
+        place_bid_as(user(3), item_id, 15).unwrap();
+    }
+
+    #[test]
+    fn min_increment_needs_live_auction() {
+        let item_id = listed(user(1));
+        with_state(|state| close_auction(state.items.get_mut(&item_id).unwrap(), &StopOutcome::Unsold, 0));
+        act_as_admin();
+        assert!(admin_set_min_increment(item_id, 5).is_err());
+        assert!(admin_set_min_increment(item_id + 1, 5).is_err());
+    }
+
+    #[test]
+    fn exact_name_search_is_case_sensitive() {
+        act_as(user(1));
+        list_items(vec![
+            ("Lamp".to_string(), String::new()),
+            ("lamp".to_string(), String::new()),
+            ("Lamp ".to_string(), String::new()),
+            ("Lamp".to_string(), String::new()),
+        ]);
+
+        assert_eq!(ids(&get_items_by_exact_name("Lamp".to_string())), vec![0, 3]);
+        assert_eq!(ids(&get_items_by_exact_name("lamp".to_string())), vec![1]);
+        assert!(get_items_by_exact_name("Lam".to_string()).is_empty());
+    }
+
+    #[test]
+    fn transfer_moves_only_active_bid_free_listings() {
+        let free = listed(user(1));
+        let with_bid = listed(user(1));
+        let closed = listed(user(1));
+        let other_seller = listed(user(4));
+        let withdrawn = listed(user(1));
+        fund(user(2), 100);
+        place_bid_as(user(2), with_bid, 5).unwrap();
+        place_bid_as(user(2), withdrawn, 5).unwrap();
+        act_as(user(2));
+        withdraw_bid(withdrawn).unwrap();
+        with_state(|state| close_auction(state.items.get_mut(&closed).unwrap(), &StopOutcome::Unsold, 0));
+
+        act_as(user(1));
+        assert!(transfer_all_listings(user(1)).is_err());
+        assert_eq!(transfer_all_listings(user(3)), Ok(2));
+
+        let owner = |item_id| get_item(item_id).unwrap().owner;
+        assert_eq!(owner(free), user(3));
+        assert_eq!(owner(withdrawn), user(3));
+        assert_eq!(owner(with_bid), user(1));
+        assert_eq!(owner(closed), user(1));
+        assert_eq!(owner(other_seller), user(4));
+        assert_eq!(transfer_all_listings(user(3)), Ok(0));
+    }
+
+    #[test]
+    fn admin_cancel_closes_unsold_and_unlocks_bids() {
+        let item_id = contested_item();
+        set_time(9 * SEC);
+
+        act_as(user(1));
+        assert!(admin_cancel_auction(item_id, "Counterfeit".to_string()).is_err());
+        act_as_admin();
+        assert!(admin_cancel_auction(item_id, "  ".to_string()).is_err());
+        admin_cancel_auction(item_id, "Counterfeit".to_string()).unwrap();
+
+        let item = get_item(item_id).unwrap();
+        assert!(!item.active);
+        assert_eq!((item.new_owner, item.closed_at), (None, Some(9 * SEC)));
+        assert_eq!(item.cancel_reason.as_deref(), Some("Counterfeit"));
+        assert!(matches!(get_item_timeline(item_id).last(), Some(TimelineEvent::Closed { cancel_reason: Some(reason), .. }) if reason == "Counterfeit"));
+        assert_eq!(get_user_summary(user(3)).locked_escrow, 0);
+        assert_eq!(get_user_summary(user(3)).available_balance, 20);
+        assert!(admin_cancel_auction(item_id, "Again".to_string()).is_err());
+    }
+
+    #[test]
+    fn admin_cancel_waits_for_settlement() {
+        let item_id = contested_item();
+        act_as_admin();
+        {
+            let _guard = SettlementGuard::new(item_id);
+            assert!(admin_cancel_auction(item_id, "Counterfeit".to_string()).is_err());
+        }
+        admin_cancel_auction(item_id, "Counterfeit".to_string()).unwrap();
+    }
+
+    #[test]
+    fn opening_bid_only_limits_the_first_bid() {
+        let item_id = listed(user(1));
+        fund(user(2), 100);
+        fund(user(3), 100);
+        act_as(user(2));
+        assert!(set_opening_bid(item_id, Some(50)).is_err());
+        act_as(user(1));
+        set_opening_bid(item_id, Some(50)).unwrap();
+
+        assert!(place_bid_as(user(2), item_id, 49).is_err());
+        place_bid_as(user(2), item_id, 50).unwrap();
+        // later bids only need to beat the current one
+        place_bid_as(user(3), item_id, 51).unwrap();
+
+        act_as(user(1));
+        assert!(set_opening_bid(item_id, None).is_err());
+    }
+
+    #[test]
+    fn paused_listings_still_take_bids() {
+        let item_id = listed(user(1));
+        act_as(user(1));
+        assert!(set_listings_paused(true).is_err());
+        act_as_admin();
+        set_listings_paused(true).unwrap();
+        assert!(get_config().listings_paused);
+
+        fund(user(2), 10);
+        place_bid_as(user(2), item_id, 10).unwrap();
+
+        act_as_admin();
+        set_listings_paused(false).unwrap();
+        assert_eq!(listed(user(1)), item_id + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "trap")]
+    fn paused_listings_reject_new_items() {
+        with_state(|state| state.config.listings_paused = true);
+        listed(user(1));
+    }
+
+    #[test]
+    fn orphaned_bids_are_found_and_pruned() {
+        let kept = contested_item();
+        with_state(|state| {
+            state.item_bids.insert(7, BTreeMap::from([(user(2), Bid { bidder: user(2), amount: 1, timestamp: None })]));
+            state.item_bids.insert(9, BTreeMap::new());
+            assert_eq!(orphaned_bid_ids(state), vec![7, 9]);
+        });
+
+        act_as(user(2));
+        assert!(find_orphaned_bids().is_err());
+        assert!(prune_orphaned_bids().is_err());
+        act_as_admin();
+        assert_eq!(find_orphaned_bids(), Ok(vec![7, 9]));
+        assert_eq!(prune_orphaned_bids(), Ok(2));
+        assert_eq!(find_orphaned_bids(), Ok(Vec::new()));
+        assert_eq!(get_bids_for_item(kept).len(), 2);
+    }
+
+    #[test]
+    fn min_account_age_counts_from_first_interaction() {
+        set_time(100 * SEC);
+        let item_id = listed(user(1));
+        act_as(user(1));
+        set_min_account_age(item_id, Some(60)).unwrap();
+        act_as(user(2));
+        assert!(set_min_account_age(item_id, None).is_err());
+
+        // the first call, even a failed one, starts the clock
+        fund(user(2), 100);
+        assert!(place_bid_as(user(2), item_id, 5).is_err());
+        set_time(159 * SEC);
+        assert!(place_bid_as(user(2), item_id, 5).is_err());
+        set_time(160 * SEC);
+        place_bid_as(user(2), item_id, 5).unwrap();
+        assert_eq!(with_state(|state| state.first_seen[&user(2)]), 100 * SEC);
+    }
+
+    #[test]
+    fn seller_sales_pair_items_with_buyers() {
+        let first = sold_at(10, user(2));
+        non_sales();
+        let second = sold_at(20, user(5));
+        let elsewhere = listed(user(4));
+        with_state(|state| close_auction(state.items.get_mut(&elsewhere).unwrap(), &StopOutcome::Sold { buyer: user(3), price: 5 }, 0));
+
+        let sales: Vec<(u64, Principal)> = get_seller_sales(user(1)).into_iter()
+            .map(|(item, buyer)| (item.id, buyer))
+            .collect();
+        // the free sale from non_sales still has a buyer
+        assert_eq!(sales.len(), 3);
+        assert!(sales.contains(&(first, user(2))));
+        assert!(sales.contains(&(second, user(5))));
+        let buyers: BTreeSet<Principal> = sales.iter().map(|(_, buyer)| *buyer).collect();
+        assert_eq!(buyers, BTreeSet::from([user(2), user(5)]));
+        assert_eq!(get_seller_sales(user(4)).len(), 1);
+        assert!(get_seller_sales(user(2)).is_empty());
+    }
+
+    #[test]
+    fn bid_velocity_counts_every_logged_bid() {
+        set_time(100 * SEC);
+        let item_id = listed(user(1));
+        let other = listed(user(1));
+        fund(user(2), 100);
+        fund(user(3), 100);
+        place_bid_as(user(2), item_id, 5).unwrap();
+        place_bid_as(user(3), item_id, 6).unwrap();
+        place_bid_as(user(3), other, 6).unwrap();
+        act_as(user(2));
+        withdraw_bid(item_id).unwrap();
+
+        // withdrawn and replaced bids still show up as activity
+        assert_eq!(get_bid_velocity(item_id, 0), 2);
+        assert_eq!(get_bid_velocity(other, 10), 1);
+        assert_eq!(get_bid_velocity(other + 1, 10), 0);
+        set_time(111 * SEC);
+        assert_eq!(get_bid_velocity(item_id, 10), 0);
+        assert_eq!(get_bid_velocity(item_id, u64::MAX), 2);
+    }
+
+    #[test]
+    fn item_owner_check() {
+        let item_id = listed(user(1));
+        assert_eq!(is_item_owner(item_id, user(1)), Some(true));
+        assert_eq!(is_item_owner(item_id, user(2)), Some(false));
+        assert_eq!(is_item_owner(item_id + 1, user(1)), None);
+    }
+
+    #[test]
+    fn leading_count_tracks_active_leads() {
+        ranked_items();
+        // user(2) leads item 0, user(3) leads items 1 and 2, user(4) leads item 4
+        assert_eq!(get_leading_count(user(2)), 1);
+        assert_eq!(get_leading_count(user(3)), 2);
+
+        with_state(|state| close_auction(state.items.get_mut(&1).unwrap(), &StopOutcome::Sold { buyer: user(3), price: 6 }, 0));
+        assert_eq!(get_leading_count(user(3)), 1);
+        place_bid_as(user(2), 2, 5).unwrap();
+        assert_eq!(get_leading_count(user(3)), 0);
+        assert_eq!(get_leading_count(user(2)), 2);
+        assert_eq!(get_leading_count(user(5)), 0);
+    }
+
+    #[test]
+    fn dashboard_counts_market_activity() {
+        ranked_items();
+        sold_at(10, user(2));
+        with_state(|state| {
+            state.deployed_at = 3;
+            state.config.listings_paused = true;
+        });
+
+        let dashboard = with_state(|state| build_dashboard(state, 4, 5));
+        assert_eq!((dashboard.total_items, dashboard.active_items, dashboard.sold_items), (6, 5, 1));
+        assert_eq!((dashboard.total_bids, dashboard.unique_bidders), (6, 3));
+        assert_eq!(dashboard.total_deposits, 300);
+        assert_eq!((dashboard.stable_memory_pages, dashboard.cycle_balance, dashboard.deployed_at), (4, 5, 3));
+        assert!(dashboard.config.listings_paused);
+    }
+
+    #[test]
+    fn dashboard_requires_admin() {
+        act_as(user(1));
+        assert!(admin_dashboard().is_err());
+    }
+
+    #[test]
+    fn winners_are_distinct_buyers() {
+        assert!(get_all_winners().is_empty());
+        sold_at(10, user(2));
+        sold_at(20, user(2));
+        non_sales();
+        let other = listed(user(1));
+        with_state(|state| close_auction(state.items.get_mut(&other).unwrap(), &StopOutcome::Sold { buyer: user(5), price: 5 }, 0));
+
+        assert_eq!(get_all_winners(), vec![user(2), user(5)]);
+    }
+
+    #[test]
+    fn median_sale_price_over_sales_only() {
+        assert_eq!(get_median_sale_price(), None);
+        non_sales();
+        assert_eq!(get_median_sale_price(), None);
+
+        sold_at(30, user(2));
+        sold_at(10, user(2));
+        sold_at(20, user(2));
+        assert_eq!(get_median_sale_price(), Some(20));
+        sold_at(25, user(2));
+        // (20 + 25) / 2 truncates towards zero
+        assert_eq!(get_median_sale_price(), Some(22));
+    }
+
+    #[test]
+    fn median_sale_price_does_not_overflow() {
+        sold_at(u64::MAX, user(2));
+        sold_at(u64::MAX - 2, user(2));
+        assert_eq!(get_median_sale_price(), Some(u64::MAX - 1));
+    }
+
+    #[test]
+    fn user_summary_combines_trades_and_escrow() {
+        let sold = contested_item();
+        settle(sold, 2);
+        let running = listed(user(3));
+        fund(user(2), 40);
+        place_bid_as(user(2), running, 15).unwrap();
+
+        let seller = get_user_summary(user(1));
+        assert_eq!((seller.items_listed, seller.total_earned, seller.items_won, seller.total_spent), (1, 18, 0, 0));
+
+        let buyer = get_user_summary(user(3));
+        assert_eq!((buyer.items_listed, buyer.items_won, buyer.total_spent, buyer.total_earned), (1, 1, 20, 0));
+        assert_eq!((buyer.locked_escrow, buyer.available_balance), (0, 0));
+
+        // user(2) lost the first auction, so only the running bid is locked
+        let bidder = get_user_summary(user(2));
+        assert_eq!((bidder.items_won, bidder.locked_escrow, bidder.available_balance), (0, 15, 35));
+
+        // once user(3)'s own listing sells, they have both bought and sold
+        settle(running, 2);
+        let trader = get_user_summary(user(3));
+        assert_eq!((trader.items_listed, trader.items_won, trader.total_spent, trader.total_earned), (1, 1, 20, 13));
+        let bidder = get_user_summary(user(2));
+        assert_eq!((bidder.items_won, bidder.total_spent, bidder.locked_escrow, bidder.available_balance), (1, 15, 0, 35));
+    }
+
+    // drive an async endpoint that completes without calling another canister
+    fn run_now<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future is waiting on an inter-canister call"),
+        }
+    }
+
+    #[test]
+    fn timed_auction_stops_taking_bids_at_deadline() {
+        set_time(100 * SEC);
+        act_as(user(1));
+        let item_id = list_item_timed("Lamp".to_string(), String::new(), 60);
+        assert_eq!(get_item(item_id).unwrap().end_time, Some(160 * SEC));
+        fund(user(2), 100);
+        place_bid_as(user(2), item_id, 5).unwrap();
+
+        set_time(160 * SEC);
+        assert!(place_bid_as(user(2), item_id, 6).is_err());
+        act_as(user(2));
+        assert!(withdraw_bid(item_id).is_err());
+    }
+
+    #[test]
+    fn finalize_closes_only_expired_auctions() {
+        set_time(100 * SEC);
+        act_as(user(1));
+        let expiring = list_item_timed("Lamp".to_string(), String::new(), 60);
+        let later = list_item_timed("Lamp".to_string(), String::new(), 120);
+        let manual = listed(user(1));
+
+        set_time(170 * SEC);
+        assert_eq!(run_now(finalize_expired_auctions()), 1);
+        let expired = get_item(expiring).unwrap();
+        assert!(!expired.active);
+        assert_eq!((expired.new_owner, expired.closed_at), (None, Some(170 * SEC)));
+        assert!(get_item(later).unwrap().active);
+        assert!(get_item(manual).unwrap().active);
+        assert_eq!(run_now(finalize_expired_auctions()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "trap")]
+    fn timed_auction_needs_a_duration() {
+        act_as(user(1));
+        list_item_timed("Lamp".to_string(), String::new(), 0);
+    }
+
+    fn not_biddable(reason: &str) -> Biddability {
+        Biddability::NotBiddable { reason: reason.to_string() }
+    }
+
+    #[test]
+    fn biddability_explains_rejections() {
+        let item_id = listed(user(1));
+        act_as(user(2));
+        assert_eq!(biddability(item_id), Biddability::Biddable);
+        assert_eq!(biddability(item_id + 1), not_biddable("Item not found."));
+        act_as(user(1));
+        assert_eq!(biddability(item_id), not_biddable("Cannot bid on your own item."));
+
+        act_as(user(2));
+        {
+            let _guard = SettlementGuard::new(item_id);
+            assert_eq!(biddability(item_id), not_biddable("Settlement for this item is in progress."));
+        }
+        with_state(|state| close_auction(state.items.get_mut(&item_id).unwrap(), &StopOutcome::Unsold, 0));
+        assert_eq!(biddability(item_id), not_biddable("Auction for this item is no longer active."));
+    }
+
+    #[test]
+    fn biddability_after_deadline() {
+        set_time(100 * SEC);
+        act_as(user(1));
+        let item_id = list_item_timed("Lamp".to_string(), String::new(), 60);
+        act_as(user(2));
+        assert_eq!(biddability(item_id), Biddability::Biddable);
+        set_time(160 * SEC);
+        assert_eq!(biddability(item_id), not_biddable("Auction has ended."));
+    }
+
+    #[test]
+    fn biddability_with_min_account_age() {
+        set_time(100 * SEC);
+        let item_id = listed(user(1));
+        with_state(|state| {
+            state.items.get_mut(&item_id).unwrap().min_account_age_secs = Some(60);
+            state.first_seen.insert(user(2), 100 * SEC);
+        });
+        act_as(user(2));
+        assert_eq!(biddability(item_id), not_biddable("This auction requires bidders to have been active for at least 60 seconds."));
+        set_time(160 * SEC);
+        assert_eq!(biddability(item_id), Biddability::Biddable);
+    }
+
+    #[test]
+    fn biddability_with_lead_cap() {
+        let led = listed(user(1));
+        let other = listed(user(1));
+        fund(user(2), 10);
+        place_bid_as(user(2), led, 5).unwrap();
+        with_state(|state| state.config.max_simultaneous_leads = Some(1));
+
+        act_as(user(2));
+        assert_eq!(biddability(other), not_biddable("You are already leading the maximum of 1 auctions."));
+        // the auction already led can still be raised
+        assert_eq!(biddability(led), Biddability::Biddable);
+    }
+
+    #[test]
+    fn biddability_ignores_amount() {
+        // no deposit is needed to be able to bid in principle
+        let item_id = listed(user(1));
+        act_as(user(2));
+        assert_eq!(biddability(item_id), Biddability::Biddable);
+        assert!(place_bid_as(user(2), item_id, 1).is_err());
+    }
+
+    #[test]
+    fn lead_cap_limits_new_leads_only() {
+        let first = listed(user(1));
+        let second = listed(user(1));
+        fund(user(2), 100);
+        fund(user(3), 100);
+        with_state(|state| state.config.max_simultaneous_leads = Some(1));
+
+        place_bid_as(user(2), first, 5).unwrap();
+        assert!(place_bid_as(user(2), second, 5).is_err());
+        // raising a lead you already hold is fine
+        place_bid_as(user(2), first, 6).unwrap();
+
+        place_bid_as(user(3), first, 7).unwrap();
+        place_bid_as(user(2), second, 5).unwrap();
+
+        with_state(|state| state.config.max_simultaneous_leads = None);
+        place_bid_as(user(3), second, 6).unwrap();
+        assert_eq!(get_leading_count(user(3)), 2);
+    }
+
+    #[test]
+    fn timeline_covers_full_lifecycle() {
+        set_time(10 * SEC);
+        let item_id = contested_item();
+        set_time(20 * SEC);
+        settle(item_id, 1);
+
+        let timeline = get_item_timeline(item_id);
+        assert_eq!(timeline.len(), 4);
+        assert!(matches!(timeline[0], TimelineEvent::Listed { at, owner } if at == 10 * SEC && owner == user(1)));
+        // equal timestamps keep the order the bids were placed in
+        assert!(matches!(timeline[1], TimelineEvent::BidPlaced { bidder, amount: 10, .. } if bidder == user(2)));
+        assert!(matches!(timeline[2], TimelineEvent::BidPlaced { bidder, amount: 20, .. } if bidder == user(3)));
+        assert!(matches!(timeline[3], TimelineEvent::Closed { at, new_owner, price: 20, cancel_reason: None } if at == 20 * SEC && new_owner == Some(user(3))));
+        assert!(get_item_timeline(item_id + 1).is_empty());
+    }
+
+    #[test]
+    fn timeline_of_unsold_item_closes_at_zero() {
+        let item_id = contested_item();
+        set_time(5 * SEC);
+        act_as_admin();
+        admin_cancel_auction(item_id, "Withdrawn".to_string()).unwrap();
+
+        let timeline = get_item_timeline(item_id);
+        assert!(matches!(timeline.last(), Some(TimelineEvent::Closed { new_owner: None, price: 0, .. })));
+    }
+
+    #[test]
+    fn lead_cap_applies_when_lead_passes_on_withdrawal() {
+        let first = listed(user(1));
+        let second = listed(user(1));
+        let third = listed(user(1));
+        for bidder in 2..=5 {
+            fund(user(bidder), 100);
+        }
+        with_state(|state| state.config.max_simultaneous_leads = Some(1));
+
+        // sybils user(3) and user(4) outbid user(2) each time so it stays under the cap
+        place_bid_as(user(5), first, 4).unwrap();
+        place_bid_as(user(2), first, 8).unwrap();
+        place_bid_as(user(3), first, 10).unwrap();
+        place_bid_as(user(2), second, 8).unwrap();
+        place_bid_as(user(4), second, 10).unwrap();
+        place_bid_as(user(2), third, 5).unwrap();
+
+        act_as(user(3));
+        withdraw_bid(first).unwrap();
+        act_as(user(4));
+        withdraw_bid(second).unwrap();
+
+        assert_eq!(get_leading_count(user(2)), 1);
+        let first_item = get_item(first).unwrap();
+        assert_eq!((first_item.highest_bidder, first_item.current_highest_bid), (Some(user(5)), 4));
+        let second_item = get_item(second).unwrap();
+        assert_eq!((second_item.highest_bidder, second_item.current_highest_bid), (None, 0));
+        // the skipped bids no longer lock funds or win at settlement
+        assert_eq!(with_state(|state| locked_balance(state, user(2))), 5);
+        assert_eq!(preview_settlement(first), Some(StopOutcome::Sold { buyer: user(5), price: 4 }));
+    }
+
+    #[test]
+    fn ledger_change_refused_with_escrow() {
+        let mut state = CanisterState::new();
+        set_ledger(&mut state, user(8)).unwrap();
+        set_ledger(&mut state, user(8)).unwrap();
+        state.deposits.insert(user(2), 10);
+        assert!(set_ledger(&mut state, user(9)).is_err());
+        assert_eq!(state.ledger_canister_id, Some(user(8)));
+
+        // an emptied balance no longer ties the canister to the ledger
+        state.deposits.insert(user(2), 0);
+        set_ledger(&mut state, user(9)).unwrap();
+        assert_eq!(state.ledger_canister_id, Some(user(9)));
+    }
+
+    #[test]
+    fn ledger_change_refused_with_live_bids() {
+        let item_id = contested_item();
+        with_state(|state| {
+            state.ledger_canister_id = Some(user(8));
+            state.deposits.clear();
+            assert!(set_ledger(state, user(9)).is_err());
+
+            close_auction(state.items.get_mut(&item_id).unwrap(), &StopOutcome::Unsold, 0);
+            set_ledger(state, user(9)).unwrap();
+        });
+    }
+
+    #[test]
+    fn bid_window_skips_untimed_bids() {
+        set_time(10 * SEC);
+        let item_id = listed(user(1));
+        push_untimed_bid(item_id, user(2), 5);
+        fund(user(3), 100);
+        set_time(20 * SEC);
+        place_bid_as(user(3), item_id, 10).unwrap();
+
+        let bids = get_bids_in_window(item_id, 0, 30 * SEC).unwrap();
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].bidder, user(3));
+        assert_eq!(bids[0].timestamp, Some(20 * SEC));
+        assert!(get_bids_in_window(item_id, 21 * SEC, 30 * SEC).unwrap().is_empty());
+        assert!(get_bids_in_window(item_id, 2, 1).is_err());
+    }
+
+    #[test]
+    fn bid_velocity_skips_untimed_bids() {
+        set_time(10 * SEC);
+        let item_id = listed(user(1));
+        push_untimed_bid(item_id, user(2), 5);
+        fund(user(3), 100);
+        place_bid_as(user(3), item_id, 10).unwrap();
+        set_time(70 * SEC);
+        place_bid_as(user(3), item_id, 20).unwrap();
+
+        assert_eq!(get_bid_velocity(item_id, 30), 1);
+        assert_eq!(get_bid_velocity(item_id, 60), 2);
+    }
+
+    #[test]
+    fn timeline_orders_untimed_bids_after_listing() {
+        set_time(10 * SEC);
+        let item_id = listed(user(1));
+        push_untimed_bid(item_id, user(2), 5);
+        fund(user(3), 100);
+        set_time(20 * SEC);
+        place_bid_as(user(3), item_id, 10).unwrap();
+
+        let timeline = get_item_timeline(item_id);
+        assert_eq!(timeline.len(), 3);
+        assert!(matches!(timeline[0], TimelineEvent::Listed { at, .. } if at == 10 * SEC));
+        assert!(matches!(timeline[1], TimelineEvent::BidPlaced { at: None, amount: 5, .. }));
+        assert!(matches!(timeline[2], TimelineEvent::BidPlaced { at: Some(at), amount: 10, .. } if at == 20 * SEC));
+    }
+}